@@ -0,0 +1,72 @@
+//! Request parsing in isolation from TLS and socket IO.
+//!
+//! `single_pass` is a whole head arriving at once. `incremental` feeds the
+//! same head in 64-byte reads and re-checks for the end of the head after
+//! each one, the way `serve_once` does, which shows the quadratic cost of
+//! rescanning the whole buffer on every read.
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+#[allow(dead_code)]
+#[path = "../src/http.rs"]
+mod http;
+
+fn firefox_like() -> Vec<u8> {
+    b"GET /json HTTP/1.1\r\n\
+      Host: 127.0.0.1:3000\r\n\
+      User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:136.0) Gecko/20100101 Firefox/136.0\r\n\
+      Accept: */*\r\n\
+      Accept-Language: en-US,en;q=0.5\r\n\
+      Accept-Encoding: gzip, deflate, br, zstd\r\n\
+      Referer: https://127.0.0.1:3000/\r\n\
+      Connection: keep-alive\r\n\
+      Sec-Fetch-Dest: empty\r\n\
+      Sec-Fetch-Mode: cors\r\n\
+      Sec-Fetch-Site: same-origin\r\n\
+      Pragma: no-cache\r\n\
+      Cache-Control: no-cache\r\n\
+      \r\n"
+        .to_vec()
+}
+
+fn many_long_headers() -> Vec<u8> {
+    let mut head = b"GET /json HTTP/1.1\r\nHost: 127.0.0.1:3000\r\n".to_vec();
+    for i in 0..100 {
+        head.extend_from_slice(format!("X-Header-{i}: {}\r\n", "v".repeat(200)).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn parse(buf: &[u8]) -> http::Request {
+    let head_len = http::head_len(buf).unwrap();
+    http::Request::parse(std::str::from_utf8(&buf[..head_len]).unwrap()).unwrap()
+}
+
+fn parse_incrementally(buf: &[u8]) -> http::Request {
+    let mut cursor = 0;
+    while http::head_len(&buf[..cursor]).is_none() {
+        cursor = (cursor + 64).min(buf.len());
+    }
+    parse(&buf[..cursor])
+}
+
+fn bench(c: &mut Criterion) {
+    let inputs = [("small", firefox_like()), ("large", many_long_headers())];
+
+    let mut group = c.benchmark_group("parse_request");
+    for (name, input) in &inputs {
+        group.bench_with_input(BenchmarkId::new("single_pass", name), input, |b, input| {
+            b.iter(|| parse(black_box(input)))
+        });
+        group.bench_with_input(BenchmarkId::new("incremental", name), input, |b, input| {
+            b.iter(|| parse_incrementally(black_box(input)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,140 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustls::pki_types::CertificateDer;
+
+/// Warns when `cert` is expired or about to be.
+///
+/// Browsers report an expired certificate with an interstitial or a failed
+/// fetch that can look a lot like the hang this repo is about, so it's worth
+/// calling out at startup. With `fail_on_expired`, an expired certificate is
+/// an error instead.
+pub fn check_expiry(
+    cert: &CertificateDer<'_>,
+    warn_within: Duration,
+    fail_on_expired: bool,
+) -> eyre::Result<()> {
+    let not_after = UNIX_EPOCH + Duration::from_secs(not_after(cert)?);
+    match not_after.duration_since(SystemTime::now()) {
+        Err(expired) => {
+            let days_ago = expired.duration().as_secs() / 86400;
+            if fail_on_expired {
+                eyre::bail!("certificate expired {days_ago} days ago");
+            }
+            tracing::warn!(days_ago, "certificate has expired");
+        }
+        Ok(remaining) if remaining < warn_within => {
+            let days_left = remaining.as_secs() / 86400;
+            tracing::warn!(days_left, "certificate expires soon");
+        }
+        Ok(remaining) => {
+            let days_left = remaining.as_secs() / 86400;
+            tracing::debug!(days_left, "certificate is valid");
+        }
+    }
+    Ok(())
+}
+
+/// Reads the `notAfter` time out of a DER certificate, as seconds since the
+/// Unix epoch.
+///
+/// This walks just enough of the structure to reach the validity period:
+///
+/// ```text
+/// Certificate ::= SEQUENCE { tbsCertificate, .. }
+/// TBSCertificate ::= SEQUENCE {
+///     [0] version OPTIONAL, serialNumber, signature, issuer,
+///     validity SEQUENCE { notBefore, notAfter }, ..
+/// }
+/// ```
+fn not_after(cert: &[u8]) -> eyre::Result<u64> {
+    let malformed = || eyre::eyre!("malformed certificate");
+
+    let (_, certificate, _) = tlv(cert, SEQUENCE).ok_or_else(malformed)?;
+    let (_, mut tbs, _) = tlv(certificate, SEQUENCE).ok_or_else(malformed)?;
+    if tbs.first() == Some(&VERSION) {
+        (_, _, tbs) = tlv(tbs, VERSION).ok_or_else(malformed)?;
+    }
+    for tag in [INTEGER, SEQUENCE, SEQUENCE] {
+        (_, _, tbs) = tlv(tbs, tag).ok_or_else(malformed)?;
+    }
+    let (_, validity, _) = tlv(tbs, SEQUENCE).ok_or_else(malformed)?;
+
+    let (tag, _, validity) = any_tlv(validity).ok_or_else(malformed)?;
+    eyre::ensure!(tag == UTC_TIME || tag == GENERALIZED_TIME, malformed());
+    let (tag, not_after, _) = any_tlv(validity).ok_or_else(malformed)?;
+    let not_after = std::str::from_utf8(not_after)?;
+    eyre::ensure!(not_after.len() >= 4 && not_after.is_ascii(), malformed());
+
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let year: i64 = parse(&not_after[..2])?;
+            (
+                if year >= 50 { 1900 + year } else { 2000 + year },
+                &not_after[2..],
+            )
+        }
+        GENERALIZED_TIME => (parse(&not_after[..4])?, &not_after[4..]),
+        _ => return Err(malformed()),
+    };
+    eyre::ensure!(rest.len() == 11 && rest.ends_with('Z'), malformed());
+    let month = parse(&rest[0..2])?;
+    let day = parse(&rest[2..4])?;
+    let hour: i64 = parse(&rest[4..6])?;
+    let minute: i64 = parse(&rest[6..8])?;
+    let second: i64 = parse(&rest[8..10])?;
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Ok(secs.try_into()?)
+}
+
+const INTEGER: u8 = 0x02;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const VERSION: u8 = 0xa0;
+
+/// Splits the element at the front of `input`, checking it has tag `tag`.
+fn tlv(input: &[u8], tag: u8) -> Option<(u8, &[u8], &[u8])> {
+    any_tlv(input).filter(|(found, _, _)| *found == tag)
+}
+
+/// Splits the element at the front of `input` into tag, contents, and the
+/// input that follows it.
+fn any_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&len, mut input) = input.split_first()?;
+
+    let len = if len < 0x80 {
+        usize::from(len)
+    } else {
+        let len_bytes = usize::from(len & 0x7f);
+        if len_bytes > size_of::<usize>() || input.len() < len_bytes {
+            return None;
+        }
+        let (len, rest) = input.split_at(len_bytes);
+        input = rest;
+        len.iter().fold(0, |len, &b| len << 8 | usize::from(b))
+    };
+
+    (input.len() >= len).then(|| {
+        let (contents, rest) = input.split_at(len);
+        (tag, contents, rest)
+    })
+}
+
+fn parse<T: std::str::FromStr>(digits: &str) -> eyre::Result<T> {
+    digits
+        .parse()
+        .map_err(|_| eyre::eyre!("bad certificate time field {digits:?}"))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date, after Howard
+/// Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
@@ -0,0 +1,244 @@
+use std::{str::FromStr, time::Duration};
+
+use crate::{cidr::Cidr, load_test::Target};
+
+/// Runtime knobs, parsed from the command line.
+///
+/// A bare `cargo run` still exercises the hang, but the defaults are no longer
+/// the original reproducer's behavior in every respect:
+///
+/// - Responses are framed correctly: CRLF line endings, a `content-length`
+///   that matches the body on every route, and a standard reason phrase.
+///   `--original-responses` sends the reproducer's bytes instead.
+/// - Unknown paths get `404` rather than `500`, and JSON errors under `/api`.
+/// - Responses carry `cache-control: no-store` unless configured otherwise.
+/// - Methods other than GET are answered (OPTIONS) or rejected with `405`,
+///   where the reproducer panicked.
+#[derive(Debug)]
+pub struct Config {
+    /// Log a `scale_up` event when the active-connection count reaches this.
+    pub scale_up_at: Option<usize>,
+    /// Log a `scale_down` event when the active-connection count falls back to this.
+    pub scale_down_at: Option<usize>,
+    /// `max_early_data_size` overrides, keyed by ALPN protocol id.
+    pub early_data_limits: Vec<(String, u32)>,
+    /// Peers allowed to send early data. Empty allows everyone.
+    pub early_data_allow: Vec<Cidr>,
+    /// Send the original reproducer's responses, framing bugs included, for
+    /// the routes it had.
+    pub original_responses: bool,
+    /// Send `Connection: close` on every response.
+    pub connection_close: bool,
+    /// Mirror each request header into an `X-Echo-<Name>` response header.
+    pub echo_headers: bool,
+    /// Idle time before TCP keepalive probes start on accepted connections.
+    pub tcp_keepalive: Option<Duration>,
+    /// Serve `/debug/no-blank-line`, a response missing the blank line
+    /// between its head and body.
+    pub no_blank_line_route: bool,
+    /// Fail a connection once this many `complete_io` calls in a row, within
+    /// one phase, have moved no bytes.
+    pub max_stalled_io: Option<usize>,
+    /// Serve a request that arrived whole in early data without reading the
+    /// normal stream for more, which is the read that hangs.
+    pub no_read_after_early_data: bool,
+    /// Leave Nagle on through the handshake, then set `TCP_NODELAY`.
+    pub nodelay_after_handshake: bool,
+    /// How long to wait, once the response is flushed, before sending
+    /// close_notify.
+    pub close_notify_delay: Option<Duration>,
+    /// How long a write may block before the client counts as a stalled
+    /// reader and the connection is dropped.
+    pub write_timeout: Duration,
+    /// Answer anything but HTTP/1.1 with `505 HTTP Version Not Supported`.
+    pub require_http11: bool,
+    /// Answer TRACE by echoing the request, instead of rejecting it with `405`.
+    pub allow_trace: bool,
+    /// How long the handshake waits on the rest of accepted early data before
+    /// it stops reading with a deadline.
+    pub early_data_timeout: Option<Duration>,
+    /// Where draining early data sits relative to the first post-handshake
+    /// `complete_io`.
+    pub early_data_order: EarlyDataOrder,
+    /// Warn at startup when the certificate expires within this long.
+    pub cert_expiry_warning: Duration,
+    /// Refuse to start with an expired certificate, rather than just warning.
+    pub fail_on_expired: bool,
+    /// Seed the randomness rustls draws on, so session tickets repeat run to
+    /// run. Test use only: anyone knowing the seed can predict them, so it's
+    /// only built in with the `seeded-rng` feature.
+    #[cfg(feature = "seeded-rng")]
+    pub rng_seed: Option<u64>,
+    /// Run as a load-generating client instead of a server, with this many
+    /// concurrent connections for this long.
+    pub load_test: Option<(usize, Duration)>,
+    /// Instead of serving, replay Firefox's 0-RTT sequence against `target`.
+    pub firefox_sequence: bool,
+    /// What the load generator points at.
+    pub target: Target,
+    /// Peers whose `Forwarded`/`X-Forwarded-For` headers are believed.
+    pub trusted_proxies: Vec<Cidr>,
+    /// `Cache-Control` values, keyed by request path.
+    pub cache_control: Vec<(String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scale_up_at: None,
+            scale_down_at: None,
+            early_data_limits: Vec::new(),
+            early_data_allow: Vec::new(),
+            original_responses: false,
+            connection_close: false,
+            echo_headers: false,
+            tcp_keepalive: None,
+            no_blank_line_route: false,
+            max_stalled_io: None,
+            no_read_after_early_data: false,
+            nodelay_after_handshake: false,
+            close_notify_delay: None,
+            write_timeout: Duration::from_secs(30),
+            require_http11: false,
+            allow_trace: false,
+            early_data_timeout: None,
+            early_data_order: EarlyDataOrder::default(),
+            cert_expiry_warning: Duration::from_secs(30 * 86400),
+            fail_on_expired: false,
+            #[cfg(feature = "seeded-rng")]
+            rng_seed: None,
+            load_test: None,
+            firefox_sequence: false,
+            target: Target::default(),
+            trusted_proxies: Vec::new(),
+            cache_control: Vec::new(),
+        }
+    }
+}
+
+/// The two orderings of the early data drain that `serve_once` can use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EarlyDataOrder {
+    /// Drain early data as soon as the handshake loop ends, before touching
+    /// the socket again.
+    #[default]
+    DrainFirst,
+    /// Call `complete_io` once after the handshake loop, and only then drain
+    /// early data. rustls keeps early data in its own buffer rather than
+    /// merging it into the normal stream, so it still has to be drained; what
+    /// changes is whether the server blocks on the socket first.
+    CompleteIoFirst,
+}
+
+impl FromStr for EarlyDataOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drain-first" => Ok(Self::DrainFirst),
+            "complete-io-first" => Ok(Self::CompleteIoFirst),
+            _ => Err("expected drain-first or complete-io-first".to_string()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> eyre::Result<Self> {
+        let mut config = Config::default();
+
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--scale-up-at" => config.scale_up_at = Some(value(&flag, args.next())?),
+                "--scale-down-at" => config.scale_down_at = Some(value(&flag, args.next())?),
+                "--original-responses" => config.original_responses = true,
+                "--connection-close" => config.connection_close = true,
+                "--echo-headers" => config.echo_headers = true,
+                "--require-http11" => config.require_http11 = true,
+                "--allow-trace" => config.allow_trace = true,
+                "--tcp-keepalive" => {
+                    config.tcp_keepalive = Some(Duration::from_secs(value(&flag, args.next())?));
+                }
+                "--no-blank-line-route" => config.no_blank_line_route = true,
+                "--max-stalled-io" => config.max_stalled_io = Some(value(&flag, args.next())?),
+                "--no-read-after-early-data" => config.no_read_after_early_data = true,
+                "--nodelay-after-handshake" => config.nodelay_after_handshake = true,
+                "--close-notify-delay" => {
+                    let millis = value(&flag, args.next())?;
+                    config.close_notify_delay = Some(Duration::from_millis(millis));
+                }
+                "--write-timeout" => {
+                    let secs = value(&flag, args.next())?;
+                    // The socket refuses a zero timeout rather than treating
+                    // it as none.
+                    eyre::ensure!(secs > 0, "{flag} must be at least 1 second");
+                    config.write_timeout = Duration::from_secs(secs);
+                }
+                "--early-data-timeout" => {
+                    let millis = value(&flag, args.next())?;
+                    config.early_data_timeout = Some(Duration::from_millis(millis));
+                }
+                "--early-data-order" => config.early_data_order = value(&flag, args.next())?,
+                "--cert-expiry-warning-days" => {
+                    let days: u64 = value(&flag, args.next())?;
+                    config.cert_expiry_warning = Duration::from_secs(days * 86400);
+                }
+                "--fail-on-expired" => config.fail_on_expired = true,
+                #[cfg(feature = "seeded-rng")]
+                "--rng-seed" => config.rng_seed = Some(value(&flag, args.next())?),
+                "--load-test" => {
+                    let concurrency = value(&flag, args.next())?;
+                    let secs = value(&flag, args.next())?;
+                    config.load_test = Some((concurrency, Duration::from_secs(secs)));
+                }
+                "--firefox-sequence" => config.firefox_sequence = true,
+                "--cache-control" => {
+                    let rule: String = value(&flag, args.next())?;
+                    let Some((path, value)) = rule.split_once('=') else {
+                        eyre::bail!("{flag} expects <path>=<value>, got {rule:?}");
+                    };
+                    config
+                        .cache_control
+                        .push((path.to_string(), value.to_string()));
+                }
+                "--early-data-allow" => config.early_data_allow.push(value(&flag, args.next())?),
+                "--trust-proxy" => config.trusted_proxies.push(value(&flag, args.next())?),
+                "--target" => config.target = value(&flag, args.next())?,
+                "--early-data-limit" => {
+                    let limit: String = value(&flag, args.next())?;
+                    let Some((protocol, bytes)) = limit.split_once('=') else {
+                        eyre::bail!("{flag} expects <protocol>=<bytes>, got {limit:?}");
+                    };
+                    let bytes = value(&flag, Some(bytes.to_string()))?;
+                    config.early_data_limits.push((protocol.to_string(), bytes));
+                }
+                _ => eyre::bail!("unrecognized flag {flag:?}"),
+            }
+        }
+
+        match (config.scale_up_at, config.scale_down_at) {
+            (Some(up), Some(down)) if down >= up => {
+                eyre::bail!("--scale-down-at ({down}) must be below --scale-up-at ({up})")
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                eyre::bail!("--scale-up-at and --scale-down-at must be given together")
+            }
+            _ => {}
+        }
+
+        Ok(config)
+    }
+}
+
+fn value<T>(flag: &str, value: Option<String>) -> eyre::Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let Some(value) = value else {
+        eyre::bail!("{flag} requires a value");
+    };
+    value
+        .parse()
+        .map_err(|err| eyre::eyre!("invalid value {value:?} for {flag}: {err}"))
+}
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Tracks how many connections are currently being served.
+///
+/// When watermarks are configured, crossing them logs a `scale_up` or
+/// `scale_down` event for external orchestration to pick up. The two
+/// watermarks form a hysteresis band, so each event fires once per crossing
+/// instead of on every connection that arrives while above the mark.
+#[derive(Debug)]
+pub struct Connections {
+    active: AtomicUsize,
+    scaled_up: AtomicBool,
+    watermarks: Option<(usize, usize)>,
+}
+
+impl Connections {
+    pub fn new(scale_up_at: Option<usize>, scale_down_at: Option<usize>) -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            scaled_up: AtomicBool::new(false),
+            watermarks: scale_up_at.zip(scale_down_at),
+        }
+    }
+
+    /// Registers a new connection; it stays active until the guard is dropped.
+    pub fn register(&self) -> ActiveConnection<'_> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some((high, _)) = self.watermarks
+            && active >= high
+            && !self.scaled_up.swap(true, Ordering::SeqCst)
+        {
+            tracing::warn!(
+                event = "scale_up",
+                active,
+                high,
+                "active connections above high watermark"
+            );
+        }
+        ActiveConnection { connections: self }
+    }
+
+    fn deregister(&self) {
+        let active = self.active.fetch_sub(1, Ordering::SeqCst) - 1;
+        if let Some((_, low)) = self.watermarks
+            && active <= low
+            && self.scaled_up.swap(false, Ordering::SeqCst)
+        {
+            tracing::warn!(
+                event = "scale_down",
+                active,
+                low,
+                "active connections below low watermark"
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ActiveConnection<'a> {
+    connections: &'a Connections,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.connections.deregister();
+    }
+}
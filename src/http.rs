@@ -0,0 +1,413 @@
+/// The head of a request: request line and headers. Bodies are never read.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    /// `None` for an HTTP/0.9 simple request, which has no version token.
+    pub version: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn parse(head: &str) -> eyre::Result<Self> {
+        let mut lines = head.lines();
+
+        let Some(request_line) = lines.next() else {
+            eyre::bail!("empty request");
+        };
+        let mut parts = request_line.split(' ');
+        let method = parts.next().unwrap_or_default().to_string();
+        let Some(target) = parts.next() else {
+            eyre::bail!("request line {request_line:?} has no target");
+        };
+        // A fragment is never meant for the server, so one that slipped
+        // through a client or proxy mustn't get in the way of routing.
+        let target = target.split('#').next().unwrap_or_default().to_string();
+        let version = parts.next().map(String::from);
+
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+            .collect();
+
+        Ok(Self {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// In the order they're written. Not a map: `Set-Cookie` in particular
+    /// can't be folded into one comma-separated field, so a repeated header
+    /// has to stay a separate entry (and line) per value.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    /// Appends a header, leaving any existing ones of the same name in place.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+/// Serializes `response` into the exact bytes to put on the wire for a request
+/// with the given method and version.
+///
+/// A `HEAD` response keeps its headers, `content-length` included, but drops
+/// the body. A bodiless status (see [`is_bodiless`]) gets neither, whatever
+/// `response.body` holds. An HTTP/0.9 request (no version) gets the bare body,
+/// since 0.9 has no status line or headers.
+pub fn encode_response(response: &Response, method: &str, version: Option<&str>) -> Vec<u8> {
+    if version.is_none() {
+        return response.body.as_bytes().to_vec();
+    }
+
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        out.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if is_bodiless(response.status) {
+        out.push_str("\r\n");
+        return out.into_bytes();
+    }
+    out.push_str(&format!("content-length: {}\r\n\r\n", response.body.len()));
+    if method != "HEAD" {
+        out.push_str(&response.body);
+    }
+    out.into_bytes()
+}
+
+/// Whether responses with `status` never have a body, and so mustn't have a
+/// `content-length` either: a client takes the head as the whole response,
+/// and a stray `content-length: 0` or empty chunk is framing it doesn't
+/// expect.
+pub fn is_bodiless(status: u16) -> bool {
+    matches!(status, 100..=199 | 204 | 304)
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        505 => "HTTP Version Not Supported",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+/// Returns the length of the request head at the start of `buf`, blank line
+/// included, or `None` if the head hasn't fully arrived yet.
+///
+/// Lines may end in either CRLF or a bare LF. An HTTP/0.9 simple request
+/// (`GET /` without a version) has no headers, so its head ends with the
+/// request line.
+pub fn head_len(buf: &[u8]) -> Option<usize> {
+    let request_line_len = buf.iter().position(|&b| b == b'\n')? + 1;
+    if buf[..request_line_len]
+        .trim_ascii()
+        .split(|&b| b == b' ')
+        .count()
+        == 2
+    {
+        return Some(request_line_len);
+    }
+
+    let mut line_start = 0;
+    while let Some(newline) = buf[line_start..].iter().position(|&b| b == b'\n') {
+        line_start += newline + 1;
+        let rest = &buf[line_start..];
+        if rest.starts_with(b"\n") {
+            return Some(line_start + 1);
+        }
+        if rest.starts_with(b"\r\n") {
+            return Some(line_start + 2);
+        }
+    }
+    None
+}
+
+/// The RFC 9218 `Priority` a request asked for.
+#[derive(Debug)]
+pub struct Priority {
+    /// 0 (most urgent) to 7. Defaults to 3.
+    pub urgency: u8,
+    /// Whether the response is useful in pieces, as it arrives.
+    pub incremental: bool,
+}
+
+/// Parses the request's `Priority` header, if it sent one.
+///
+/// Members that are unknown or out of range are ignored, as the RFC asks,
+/// leaving the defaults in place. This is informational only: responses go
+/// out in the order they're ready, since there's one per connection.
+pub fn priority(request: &Request) -> Option<Priority> {
+    let (_, value) = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("priority"))?;
+
+    let mut priority = Priority {
+        urgency: 3,
+        incremental: false,
+    };
+    for member in value.split(',') {
+        let member = member.split(';').next().unwrap_or_default().trim();
+        // A bare key is shorthand for the boolean true, `?1`.
+        match member.split_once('=').unwrap_or((member, "?1")) {
+            ("u", urgency) => {
+                if let Ok(urgency @ 0..=7) = urgency.parse() {
+                    priority.urgency = urgency;
+                }
+            }
+            ("i", "?1") => priority.incremental = true,
+            ("i", "?0") => priority.incremental = false,
+            _ => {}
+        }
+    }
+    Some(priority)
+}
+
+/// Copies every request header into an `X-Echo-<Name>` response header.
+///
+/// Headers whose name isn't a valid token, or whose value carries control
+/// characters (notably CR/LF, which would let a client split the response),
+/// are dropped rather than echoed.
+pub fn echo_headers(request: &Request, mut response: Response) -> Response {
+    for (name, value) in &request.headers {
+        if !is_token(name) || value.bytes().any(|b| b != b'\t' && b.is_ascii_control()) {
+            tracing::warn!(name, value, "not echoing unsafe header");
+            continue;
+        }
+        response = response.header(format!("X-Echo-{name}"), value);
+    }
+    response
+}
+
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Parses `head`, which must be valid: the fixture every test that needs a
+/// `Request` builds it with.
+#[cfg(test)]
+pub fn request(head: &str) -> Request {
+    Request::parse(head).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_strips_the_fragment_from_the_target() {
+        let target = |head| request(head).target;
+        assert_eq!(target("GET /json#section HTTP/1.1\r\n\r\n"), "/json");
+        assert_eq!(
+            target("GET /json?v=2#section HTTP/1.1\r\n\r\n"),
+            "/json?v=2"
+        );
+    }
+
+    fn priority_of(value: &str) -> (u8, bool) {
+        let request = request(&format!("GET / HTTP/1.1\r\nPriority: {value}\r\n\r\n"));
+        let priority = priority(&request).unwrap();
+        (priority.urgency, priority.incremental)
+    }
+
+    #[test]
+    fn priority_parses_urgency_and_bare_incremental() {
+        assert_eq!(priority_of("u=1, i"), (1, true));
+    }
+
+    #[test]
+    fn priority_ignores_out_of_range_urgency() {
+        assert_eq!(priority_of("u=8"), (3, false));
+        assert_eq!(priority_of("u=-1, i"), (3, true));
+    }
+
+    #[test]
+    fn priority_reads_explicit_false_incremental() {
+        assert_eq!(priority_of("u=5, i=?0"), (5, false));
+    }
+
+    #[test]
+    fn priority_is_none_without_the_header() {
+        assert!(priority(&request("GET / HTTP/1.1\r\n\r\n")).is_none());
+    }
+
+    #[test]
+    fn echo_headers_copies_safe_headers() {
+        let request = request("GET / HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n");
+        let response = echo_headers(&request, Response::new(200));
+        assert_eq!(
+            response.headers,
+            [
+                ("X-Echo-Host".to_string(), "localhost".to_string()),
+                ("X-Echo-Accept".to_string(), "*/*".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn echo_headers_drops_names_that_arent_tokens() {
+        let request = request("GET / HTTP/1.1\r\nBad Name: value\r\nBad\"Quote: value\r\n\r\n");
+        let response = echo_headers(&request, Response::new(200));
+        assert!(response.headers.is_empty());
+    }
+
+    #[test]
+    fn echo_headers_drops_values_with_control_characters() {
+        let mut request = request("GET / HTTP/1.1\r\n\r\n");
+        request.headers = vec![
+            ("Split".to_string(), "a\r\nSet-Cookie: injected".to_string()),
+            ("Nul".to_string(), "a\0b".to_string()),
+            ("Escape".to_string(), "a\x1bb".to_string()),
+        ];
+        let response = echo_headers(&request, Response::new(200));
+        assert!(response.headers.is_empty());
+    }
+
+    #[test]
+    fn echo_headers_keeps_tabs_in_values() {
+        let mut request = request("GET / HTTP/1.1\r\n\r\n");
+        request.headers = vec![("Tabbed".to_string(), "a\tb".to_string())];
+        let response = echo_headers(&request, Response::new(200));
+        assert_eq!(
+            response.headers,
+            [("X-Echo-Tabbed".to_string(), "a\tb".to_string())]
+        );
+    }
+
+    #[test]
+    fn head_len_waits_for_a_request_line_split_across_reads() {
+        // Early data carrying only the front of the request line.
+        assert_eq!(head_len(b"GET /js"), None);
+
+        let head = b"GET /json HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(head_len(&head[.."GET /json HTTP/1.1\r\n".len()]), None);
+        assert_eq!(head_len(head), Some(head.len()));
+    }
+
+    #[test]
+    fn head_len_accepts_bare_lf_line_endings() {
+        let head = b"GET /json HTTP/1.1\nHost: localhost\n\n";
+        assert_eq!(head_len(head), Some(head.len()));
+        assert_eq!(head_len(&head[..head.len() - 1]), None);
+    }
+
+    #[test]
+    fn head_len_ends_an_http09_request_at_the_request_line() {
+        assert_eq!(head_len(b"GET /\r\n"), Some(7));
+        assert_eq!(head_len(b"GET /\n"), Some(6));
+        assert_eq!(head_len(b"GET /"), None);
+    }
+
+    #[test]
+    fn bodiless_statuses_have_no_content_length_or_body() {
+        for status in [204, 304] {
+            let response = Response::new(status)
+                .header("x-request-id", "1")
+                .body("ignored");
+            let encoded = encode_response(&response, "GET", Some("HTTP/1.1"));
+            let expected = format!(
+                "HTTP/1.1 {status} {}\r\nx-request-id: 1\r\n\r\n",
+                reason(status)
+            );
+            assert_eq!(String::from_utf8(encoded).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn encode_response_writes_status_line_headers_and_body() {
+        let response = Response::new(200)
+            .header("content-type", "application/json")
+            .body("{}\n");
+        let encoded = encode_response(&response, "GET", Some("HTTP/1.1"));
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             content-type: application/json\r\n\
+             content-length: 3\r\n\
+             \r\n\
+             {}\n"
+        );
+    }
+
+    #[test]
+    fn encode_response_writes_each_set_cookie_on_its_own_line() {
+        let response = Response::new(200)
+            .header("set-cookie", "a=1; Secure")
+            .header("set-cookie", "b=2; Secure");
+        let encoded = encode_response(&response, "GET", Some("HTTP/1.1"));
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             set-cookie: a=1; Secure\r\n\
+             set-cookie: b=2; Secure\r\n\
+             content-length: 0\r\n\
+             \r\n"
+        );
+    }
+
+    #[test]
+    fn encode_response_counts_body_bytes_not_chars() {
+        let response = Response::new(200).body("é");
+        let encoded = encode_response(&response, "GET", Some("HTTP/1.1"));
+        assert!(
+            String::from_utf8(encoded)
+                .unwrap()
+                .contains("\r\ncontent-length: 2\r\n")
+        );
+    }
+
+    #[test]
+    fn encode_response_keeps_content_length_but_drops_the_body_for_head() {
+        let response = Response::new(200).body("hello");
+        let encoded = encode_response(&response, "HEAD", Some("HTTP/1.1"));
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn encode_response_sends_only_the_body_for_http09() {
+        let response = Response::new(200)
+            .header("content-type", "text/html")
+            .body("<html></html>\n");
+        let encoded = encode_response(&response, "GET", None);
+        assert_eq!(encoded, b"<html></html>\n");
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+
+use crate::{http, socket::is_timeout};
+
+/// How long a connection may sit without progress before it counts as hung.
+const HANG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the load generator sends its requests, given as `https://host:port/path`.
+#[derive(Clone, Debug)]
+pub struct Target {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 3000,
+            path: "/json".to_string(),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = url.strip_prefix("https://") else {
+            return Err("only https:// targets are supported".to_string());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "invalid port")?),
+            None => (authority, 443),
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// How a single request went.
+#[derive(Debug)]
+enum Outcome {
+    Completed { latency: Duration, early_data: bool },
+    Hung,
+    Failed(eyre::Report),
+}
+
+/// Hammers `target` from `concurrency` threads for `duration`, then reports
+/// how many requests completed, hung, or failed.
+///
+/// Each thread alternates between a fresh full handshake and a resumption
+/// that sends the request as early data, so both paths are always under load.
+pub fn run(target: &Target, concurrency: usize, duration: Duration) -> eyre::Result<()> {
+    let fresh = Arc::new(client_config(false)?);
+    let resuming = Arc::new(client_config(true)?);
+    let deadline = Instant::now() + duration;
+
+    tracing::info!(?target, concurrency, ?duration, "starting load test");
+
+    let outcomes = std::thread::scope(|s| {
+        let workers = (0..concurrency)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut outcomes = Vec::new();
+                    for attempt in 0.. {
+                        if Instant::now() >= deadline {
+                            break;
+                        }
+                        let config = if attempt % 2 == 0 { &fresh } else { &resuming };
+                        outcomes.push(request(config.clone(), target));
+                    }
+                    outcomes
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("load test worker panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut latencies = Vec::new();
+    let (mut early_data, mut hung, mut failed) = (0, 0, 0);
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Completed {
+                latency,
+                early_data: sent_early,
+            } => {
+                latencies.push(latency);
+                early_data += usize::from(sent_early);
+            }
+            Outcome::Hung => hung += 1,
+            Outcome::Failed(err) => {
+                tracing::debug!(?err, "request failed");
+                failed += 1;
+            }
+        }
+    }
+    latencies.sort();
+
+    let percentile = |p: usize| latencies.get(latencies.len() * p / 100).copied();
+    tracing::info!(
+        completed = latencies.len(),
+        early_data,
+        hung,
+        failed,
+        p50 = ?percentile(50),
+        p99 = ?percentile(99),
+        max = ?latencies.last(),
+        "load test finished"
+    );
+
+    Ok(())
+}
+
+/// Replays the request sequence Firefox uses for the demo page's `fetch`, as
+/// closely as a rustls client can, and fails unless it's served.
+///
+/// The first connection is a full handshake that only exists to obtain a
+/// session ticket. The second resumes with it and sends the request, with the
+/// `Cookie` and `Origin` headers a `credentials: 'include'` fetch carries, as
+/// early data. That resumed request is the one that hangs in the browser.
+///
+/// What this can't reproduce: Firefox's NSS ClientHello (extension order,
+/// cipher suites, GREASE), its connection pooling and speculative connects,
+/// its choice of when a ticket is reused, and its HTTP/2 negotiation. The
+/// server keys early data handling on none of those, but a hang that only
+/// appears in the browser would point at one of them.
+pub fn firefox_sequence(target: &Target) -> eyre::Result<()> {
+    let config = Arc::new(client_config(true)?);
+    let origin = format!("https://{}:{}", target.host, target.port);
+    let headers = [
+        ("User-Agent", FIREFOX_USER_AGENT),
+        ("Accept", "*/*"),
+        ("Accept-Language", "en-US,en;q=0.5"),
+        ("Referer", &format!("{origin}/")),
+        ("Origin", &origin),
+        ("Cookie", "session=firefox-sequence"),
+        ("Sec-Fetch-Dest", "empty"),
+        ("Sec-Fetch-Mode", "cors"),
+        ("Sec-Fetch-Site", "same-origin"),
+    ];
+
+    fetch(config.clone(), target, &headers)?;
+    tracing::info!("full handshake served, ticket obtained");
+
+    let (response, early_data) = match fetch(config, target, &headers) {
+        Err(err) if is_timeout(&err) => eyre::bail!("resumed request hung"),
+        result => result?,
+    };
+    eyre::ensure!(
+        early_data,
+        "server did not accept the request as early data"
+    );
+    eyre::ensure!(
+        response.starts_with(b"HTTP/1.1 2"),
+        "resumed request was not successful"
+    );
+    tracing::info!("resumed request served from early data");
+
+    Ok(())
+}
+
+const FIREFOX_USER_AGENT: &str =
+    "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+
+fn request(config: Arc<ClientConfig>, target: &Target) -> Outcome {
+    let start = Instant::now();
+    match fetch(config, target, &[]) {
+        Ok((response, early_data)) if response.starts_with(b"HTTP/1.1 2") => Outcome::Completed {
+            latency: start.elapsed(),
+            early_data,
+        },
+        Ok((response, _)) => {
+            let status_line = response.split(|&b| b == b'\r').next().unwrap_or_default();
+            let status_line = String::from_utf8_lossy(status_line);
+            Outcome::Failed(eyre::eyre!("unexpected response {status_line:?}"))
+        }
+        Err(err) if is_timeout(&err) => Outcome::Hung,
+        Err(err) => Outcome::Failed(err),
+    }
+}
+
+/// Runs one request on a new connection, returning the raw response and
+/// whether it was sent as accepted early data.
+fn fetch(
+    config: Arc<ClientConfig>,
+    target: &Target,
+    headers: &[(&str, &str)],
+) -> eyre::Result<(Vec<u8>, bool)> {
+    let mut sock = TcpStream::connect((target.host.as_str(), target.port))?;
+    sock.set_read_timeout(Some(HANG_TIMEOUT))?;
+    sock.set_write_timeout(Some(HANG_TIMEOUT))?;
+
+    let server_name = ServerName::try_from(target.host.clone())?;
+    let mut tls = ClientConnection::new(config, server_name)?;
+
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}:{}\r\n",
+        target.path, target.host, target.port
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    let mut sent_early = false;
+    if let Some(mut early_data) = tls.early_data()
+        && early_data.bytes_left() >= request.len()
+    {
+        early_data.write_all(request.as_bytes())?;
+        sent_early = true;
+    }
+
+    while tls.is_handshaking() {
+        tls.complete_io(&mut sock)?;
+    }
+
+    // Rejected early data is dropped by the server, so it has to be resent.
+    let early_data = sent_early && tls.is_early_data_accepted();
+    if !early_data {
+        tls.writer().write_all(request.as_bytes())?;
+    }
+
+    let response = read_response(&mut tls, &mut sock)?;
+    check_framing(&response)?;
+    Ok((response, early_data))
+}
+
+/// Reads the response until the server's close_notify.
+pub fn read_response(tls: &mut ClientConnection, sock: &mut TcpStream) -> eyre::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    loop {
+        tls.complete_io(sock)?;
+        match tls.reader().read_to_end(&mut response) {
+            Ok(_) => return Ok(response),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Checks the body is exactly as long as `content-length` says, up to the
+/// close_notify that ended the read.
+///
+/// A short body leaves a real client waiting for bytes that never come, and a
+/// missing `content-length` leaves it waiting for the connection to close;
+/// either presents as a hang in the browser. Bodiless statuses are the
+/// exception: they must have neither a body nor a `content-length`.
+pub fn check_framing(response: &[u8]) -> eyre::Result<()> {
+    let Some(head_len) = http::head_len(response) else {
+        eyre::bail!("response head never completed");
+    };
+    let head = std::str::from_utf8(&response[..head_len])?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_default();
+    let content_length = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim());
+    if http::is_bodiless(status) {
+        eyre::ensure!(
+            content_length.is_none() && response.len() == head_len,
+            "{status} response has a content-length or body"
+        );
+        return Ok(());
+    }
+    let Some(content_length) = content_length else {
+        eyre::bail!("response has no content-length");
+    };
+    let content_length: usize = content_length.parse()?;
+
+    let body_len = response.len() - head_len;
+    eyre::ensure!(
+        body_len == content_length,
+        "content-length is {content_length} but the body is {body_len} bytes"
+    );
+    Ok(())
+}
+
+pub fn client_config(resumption: bool) -> eyre::Result<ClientConfig> {
+    let cert = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("self_signed_certs")
+        .join("cert.pem");
+    let cert = CertificateDer::from_pem_file(&cert)?;
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert {
+            cert,
+            algorithms: provider.signature_verification_algorithms,
+        }))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    if resumption {
+        config.enable_early_data = true;
+    } else {
+        config.resumption = rustls::client::Resumption::disabled();
+    }
+
+    Ok(config)
+}
+
+/// Trusts exactly the bundled certificate.
+///
+/// It's self-signed, has no subjectAltName, and has expired, so regular
+/// webpki verification would reject it. Pinning keeps the check meaningful
+/// while still letting the handshake signatures be verified normally.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if *end_entity != self.cert {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::UnknownIssuer,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
@@ -1,15 +1,37 @@
 use std::{
     io::{ErrorKind, Read, Write},
     net::TcpStream,
+    ops::ControlFlow,
     path::PathBuf,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use eyre::Context;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
-use tracing;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use crate::{
+    config::{Config, EarlyDataOrder},
+    connections::Connections,
+    http::{Request, Response},
+    middleware::Middlewares,
+    tls::TlsConfigs,
+};
+
+mod cert;
+mod cidr;
+mod config;
+mod connections;
+mod http;
+mod load_test;
+mod middleware;
+mod proxy;
+#[cfg(feature = "seeded-rng")]
+mod seeded_random;
+mod socket;
+mod tls;
+
 fn main() -> eyre::Result<()> {
     tracing_subscriber::registry()
         .with(
@@ -19,38 +41,70 @@ fn main() -> eyre::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let certs = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("self_signed_certs")
-        .join("cert.pem");
-    let certs = CertificateDer::pem_file_iter(&certs)?.collect::<Result<Vec<_>, _>>()?;
-    let key = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("self_signed_certs")
-        .join("key.pem");
-    let key = PrivateKeyDer::from_pem_file(&key)?;
+    let config = Config::from_args()?;
+    let config = &config;
 
-    let mut server_config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+    if let Some((concurrency, duration)) = config.load_test {
+        return load_test::run(&config.target, concurrency, duration);
+    }
+    if config.firefox_sequence {
+        return load_test::firefox_sequence(&config.target);
+    }
 
-    // COMMENT OUT THIS LINE and Firefox should consistently handle requests.
-    server_config.max_early_data_size = 1024;
+    let server_config = server_config(config)?;
+    let tls_configs = TlsConfigs::new(
+        server_config,
+        &config.early_data_limits,
+        &config.early_data_allow,
+    );
+    let tls_configs = &tls_configs;
+
+    let middlewares = Middlewares::from_config(config);
+    let middlewares = &middlewares;
 
-    let server_config = Arc::new(server_config);
+    let connections = Connections::new(config.scale_up_at, config.scale_down_at);
+    let connections = &connections;
 
     std::thread::scope(|s| -> eyre::Result<()> {
         s.spawn(move || -> eyre::Result<()> {
             let listener = std::net::TcpListener::bind("127.0.0.1:3000")?;
             tracing::info!("spawning www server on {listener:?}");
+            match config.tcp_keepalive {
+                Some(idle) => tracing::info!(?idle, "tcp keepalive enabled"),
+                None => tracing::info!("tcp keepalive disabled"),
+            }
+            tracing::info!(early_data_order = ?config.early_data_order, "early data strategy");
+            tracing::info!(write_timeout = ?config.write_timeout, "write timeout");
 
             loop {
-                let (conn, peer_sa) = listener.accept()?;
+                let (mut conn, peer_sa) = listener.accept()?;
                 tracing::info!("serving connection from {peer_sa:?}");
+                let active = connections.register();
 
-                let tls = rustls::ServerConnection::new(server_config.clone())?;
-                let tls = rustls::Connection::Server(tls);
+                if let Some(idle) = config.tcp_keepalive
+                    && let Err(err) = socket::set_keepalive(&conn, idle)
+                {
+                    tracing::warn!(?err, "failed to enable tcp keepalive");
+                }
+                if let Err(err) = conn.set_write_timeout(Some(config.write_timeout)) {
+                    tracing::warn!(?err, "failed to set write timeout");
+                }
 
-                s.spawn(|| {
-                    serve_once(conn, tls).context("conn serve failed").unwrap();
+                s.spawn(move || {
+                    let _active = active;
+
+                    let tls = match tls::accept(&mut conn, tls_configs) {
+                        Ok(tls) => tls,
+                        Err(err) => {
+                            tracing::error!(?err, "tls accept failed");
+                            return;
+                        }
+                    };
+                    let tls = rustls::Connection::Server(tls);
+
+                    serve_once(conn, tls, config, middlewares)
+                        .context("conn serve failed")
+                        .unwrap();
                 });
             }
         });
@@ -59,14 +113,98 @@ fn main() -> eyre::Result<()> {
     })
 }
 
-fn serve_once(mut conn: TcpStream, tls: rustls::Connection) -> eyre::Result<()> {
+/// Builds the base TLS config: the bundled certificate, and early data on.
+fn server_config(config: &Config) -> eyre::Result<Arc<rustls::ServerConfig>> {
+    let certs = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("self_signed_certs")
+        .join("cert.pem");
+    let certs = CertificateDer::pem_file_iter(&certs)?.collect::<Result<Vec<_>, _>>()?;
+    if let Some(leaf) = certs.first() {
+        cert::check_expiry(leaf, config.cert_expiry_warning, config.fail_on_expired)?;
+    }
+    let key = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("self_signed_certs")
+        .join("key.pem");
+    let key = PrivateKeyDer::from_pem_file(&key)?;
+
+    #[cfg(feature = "seeded-rng")]
+    let builder = match config.rng_seed {
+        Some(seed) => {
+            tracing::warn!(
+                seed,
+                "tls randomness is seeded and predictable, never do this in production"
+            );
+            let provider = rustls::crypto::CryptoProvider {
+                secure_random: Box::leak(Box::new(seeded_random::SeededRandom::new(seed))),
+                ..rustls::crypto::aws_lc_rs::default_provider()
+            };
+            rustls::ServerConfig::builder_with_provider(Arc::new(provider))
+                .with_safe_default_protocol_versions()?
+        }
+        None => rustls::ServerConfig::builder(),
+    };
+    #[cfg(not(feature = "seeded-rng"))]
+    let builder = rustls::ServerConfig::builder();
+    let mut server_config = builder
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    // COMMENT OUT THIS LINE and Firefox should consistently handle requests.
+    server_config.max_early_data_size = 1024;
+
+    Ok(Arc::new(server_config))
+}
+
+fn serve_once(
+    mut conn: TcpStream,
+    tls: rustls::Connection,
+    config: &Config,
+    middlewares: &Middlewares,
+) -> eyre::Result<()> {
     let rustls::Connection::Server(mut tls) = tls else {
         panic!("How did we get a client connection in here?");
     };
 
+    let mut request = vec![0u8; 4096];
+    let mut cursor = 0;
+
+    let mut handshake_io = tls::IoProgress::new("handshake", config.max_stalled_io);
+    let mut request_io = tls::IoProgress::new("request", config.max_stalled_io);
+
+    // Taken, to arm the deadline, once early data has been accepted; it's
+    // only armed once per connection.
+    let mut early_data_timeout = config.early_data_timeout;
+    let mut deadline = socket::ReadDeadline::new(&conn);
+
+    let mut traced = tls::RecordTrace::new(&mut deadline);
     while tls.is_handshaking() {
-        match tls.complete_io(&mut conn) {
-            Ok(_) => {}
+        if tls.early_data().is_some()
+            && let Some(timeout) = early_data_timeout.take()
+        {
+            traced.get_mut().arm(Instant::now() + timeout);
+        }
+
+        trace_state("handshake", &mut tls);
+        match tls.complete_io(&mut traced) {
+            Ok((rd, wr)) => handshake_io.record(rd + wr > 0)?,
+            Err(_) if traced.get_ref().expired() => {
+                // rustls has no way to give up on the rest of the early data
+                // and finish the handshake without it: the client's
+                // EndOfEarlyData has to arrive first. So the only way to stop
+                // waiting is to drop the connection. Records that arrived
+                // before the deadline may still be waiting to be decrypted, so
+                // process them first, to log all the early data there was.
+                if let Err(err) = tls.process_new_packets() {
+                    tracing::debug!(?err, "processing the last records failed");
+                }
+                cursor += drain_early_data(&mut tls, &mut request[cursor..]);
+                tracing::warn!(
+                    timeout = ?config.early_data_timeout,
+                    bytes_read = cursor,
+                    "early data deadline fired, dropping the connection"
+                );
+                return Ok(());
+            }
             Err(err) => {
                 tracing::error!(?err, "complete_io failed");
                 return Ok(());
@@ -75,36 +213,51 @@ fn serve_once(mut conn: TcpStream, tls: rustls::Connection) -> eyre::Result<()>
         let kind = tls.handshake_kind();
         tracing::info!(?kind, "tls handshake finished");
     }
+    deadline.disarm()?;
 
-    let mut request = vec![0u8; 4096];
-    let mut cursor = 0;
+    if config.nodelay_after_handshake {
+        conn.set_nodelay(true)?;
+        tracing::info!("handshake done, disabled nagle for the response phase");
+    }
 
     let has_early_data = tls.early_data().is_some();
+    tracing::trace!(
+        kind = ?tls.handshake_kind(),
+        version = ?tls.protocol_version(),
+        suite = ?tls.negotiated_cipher_suite().map(|suite| suite.suite()),
+        has_early_data,
+        "handshake outcome"
+    );
+
+    if has_early_data && config.early_data_order == EarlyDataOrder::CompleteIoFirst {
+        let (rd, wr) = tls.complete_io(&mut conn)?;
+        request_io.record(rd + wr > 0)?;
+    }
 
-    if let Some(mut early_data) = tls.early_data() {
-        loop {
-            let bytes_read = match early_data.read(&mut request[cursor..]) {
-                Ok(0) => break,
-                Ok(bytes) => bytes,
-                Err(err) => {
-                    tracing::error!(?err, "failed to read early data");
-                    panic!("boom");
-                },
-            };
-            cursor += bytes_read;
-        }
+    if has_early_data {
+        cursor += drain_early_data(&mut tls, &mut request[cursor..]);
         let tls_wants_read = tls.wants_read();
         tracing::info!(bytes_read = cursor, tls_wants_read, "received early data");
     }
 
-    // Set this to false, and requests sent as early data will succeed.
-    let read_from_tls_anyway = true;
-    if !has_early_data || read_from_tls_anyway {
+    // False with --no-read-after-early-data, and then requests sent as early
+    // data succeed.
+    let read_from_tls_anyway = !config.no_read_after_early_data;
+    // Early data may only carry the front of the request (even a fragment of
+    // the request line), in which case the rest follows on the normal stream.
+    let early_data_complete = http::head_len(&request[..cursor]).is_some();
+    let early_data_bytes = cursor;
+    let mut reads = 0;
+    if !has_early_data || read_from_tls_anyway || !early_data_complete {
         tracing::info!("no early data reading from the tcp stream");
         loop {
-            tls.complete_io(&mut conn)?;
+            trace_state("request", &mut tls);
+            let (rd, wr) = tls.complete_io(&mut conn)?;
             let mut reader = tls.reader();
+            reads += 1;
             let bytes_read = match reader.read(&mut request[cursor..]) {
+                Ok(0) if cursor == request.len() => eyre::bail!("request head too large"),
+                Ok(0) => eyre::bail!("connection closed before the request head was complete"),
                 Ok(bytes) => bytes,
                 Err(err) if err.kind() == ErrorKind::WouldBlock => 0,
                 otherwise => {
@@ -113,78 +266,232 @@ fn serve_once(mut conn: TcpStream, tls: rustls::Connection) -> eyre::Result<()>
                 }
             };
             cursor += bytes_read;
+            request_io.record(rd + wr + bytes_read > 0)?;
 
-            // lol trust the client
-            if unsafe {
-                std::str::from_utf8_unchecked(&request[..cursor])
-                    .lines()
-                    .last()
-                    == Some("")
-            } {
+            if http::head_len(&request[..cursor]).is_some() {
                 break;
             }
         }
     }
+    tracing::debug!(
+        handshake = handshake_io.calls,
+        request = request_io.calls,
+        "complete_io calls per phase"
+    );
 
     request.truncate(cursor);
     let request = String::from_utf8(request)?;
+    let mut request = Request::parse(&request)?;
+    tracing::info!(
+        reads,
+        bytes = cursor,
+        early_data_bytes,
+        terminator = if early_data_complete { "early_data" } else { "stream" },
+        "request head complete"
+    );
+
+    let req = format!("{} {}", request.method, request.target);
+    let client = proxy::client_ip(conn.peer_addr()?.ip(), &request, &config.trusted_proxies);
+    let _span = tracing::info_span!("request", req, %client);
+    let _span = _span.enter();
 
-    // Check the requested path
-    let request_line = request.lines().next().unwrap();
-    if !request_line.starts_with("GET") {
-        panic!("only GET is accepted");
+    if let Some(priority) = http::priority(&request) {
+        tracing::info!(
+            urgency = priority.urgency,
+            incremental = priority.incremental,
+            "requested priority"
+        );
     }
 
-    let req = request_line
-        .split(' ')
-        .take(2)
-        .collect::<Vec<_>>()
-        .join(" ");
-    let _span = tracing::info_span!("request", req);
-    let _span = _span.enter();
+    let mut malformed = false;
+    let resp = match middlewares.apply_request(&mut request) {
+        ControlFlow::Break(resp) => resp,
+        ControlFlow::Continue(()) => match (request.method.as_str(), request.target.as_str()) {
+            ("TRACE", _) => trace(&request),
+            ("OPTIONS", "*" | "/" | "/json") => options(&middleware::allowed_methods(config)),
+            ("OPTIONS", "/debug/no-blank-line") if config.no_blank_line_route => {
+                options(&middleware::allowed_methods(config))
+            }
+            (_, "/") => index(),
+            (_, "/json") => json(),
+            (_, "/debug/no-blank-line") if config.no_blank_line_route => {
+                malformed = request.version.is_some();
+                no_blank_line()
+            }
+            _otherwise => not_found(),
+        },
+    };
+    let resp = middlewares.apply_response(&request, resp);
+
+    if socket::peer_disconnected(&conn) {
+        tracing::warn!("request cancelled, client disconnected before the response");
+        return Ok(());
+    }
 
-    let path_segment = request_line
-        .split(' ')
-        .find(|seg| seg.starts_with('/'))
-        .unwrap();
+    let original = config.original_responses.then(|| original_response(&request, &resp));
+    let mut resp = match original.flatten() {
+        Some(original) => {
+            tracing::info!("sending the original reproducer's response bytes");
+            original.into_bytes()
+        }
+        None => http::encode_response(&resp, &request.method, request.version.as_deref()),
+    };
+    if malformed {
+        // Drop the CRLF that ends the head, so the body runs straight on from
+        // the last header.
+        let head_len = http::head_len(&resp).expect("encoded responses have a head");
+        resp.drain(head_len - 2..head_len);
+        tracing::warn!("sending intentionally malformed response, no blank line before the body");
+    }
+    match respond(&resp, conn, tls, config.close_notify_delay) {
+        Err(err) if socket::is_timeout(&err) => {
+            tracing::warn!(
+                write_timeout = ?config.write_timeout,
+                "stalled writer, client stopped reading; closing connection"
+            );
+            Ok(())
+        }
+        Err(err) if socket::is_disconnect(&err) => {
+            tracing::warn!(?err, "request cancelled, client disconnected during the response");
+            Ok(())
+        }
+        result => result,
+    }
+}
 
-    let resp = match path_segment {
-        "/" => index(),
-        "/json" => json(),
-        _otherwise => error(),
+/// Reads all the early data rustls has buffered so far into `buf`, returning
+/// how many bytes that was.
+fn drain_early_data(tls: &mut rustls::ServerConnection, buf: &mut [u8]) -> usize {
+    let Some(mut early_data) = tls.early_data() else {
+        return 0;
     };
 
-    respond(resp, conn, tls)
+    let mut cursor = 0;
+    loop {
+        // `early_data` holds `tls` mutably, so the state can't be traced here.
+        let bytes_read = match early_data.read(&mut buf[cursor..]) {
+            Ok(0) => break,
+            Ok(bytes) => bytes,
+            Err(err) => {
+                tracing::error!(?err, "failed to read early data");
+                panic!("boom");
+            }
+        };
+        cursor += bytes_read;
+    }
+    cursor
+}
+
+/// Logs, at trace level, the rustls state a `serve_once` loop is about to act on.
+fn trace_state(phase: &'static str, tls: &mut rustls::ServerConnection) {
+    let state = (
+        tls.is_handshaking(),
+        tls.wants_read(),
+        tls.wants_write(),
+        tls.early_data().is_some(),
+    );
+    tracing::trace!(
+        phase,
+        ?state,
+        "(is_handshaking, wants_read, wants_write, has_early_data)"
+    );
 }
 
 #[tracing::instrument]
-fn index() -> String {
+fn index() -> Response {
     tracing::info!("generated reply");
-    INDEX_HTML_TEMPLATE
-        .replace("{content_len}", &format!("{}", INDEX_HTML.len() + 2))
-        .replace("{index_html}", INDEX_HTML)
+    Response::new(200)
+        .header("content-type", "text/html")
+        .body(INDEX_HTML)
 }
 
 #[tracing::instrument]
-fn json() -> String {
+fn json() -> Response {
+    tracing::info!("generated reply");
+    Response::new(200)
+        .header("content-type", "application/json")
+        .body(JSON)
+}
+
+/// What the original reproducer sent where `response` is the answer to a GET
+/// of one of its routes, byte for byte: LF line endings, an index page whose
+/// `content-length` doesn't match what follows the head, a `/json` with no
+/// `content-length` at all, and a `500` for any other path. `None` for
+/// everything the reproducer didn't serve, which is encoded as usual.
+fn original_response(request: &Request, response: &Response) -> Option<String> {
+    if request.method != "GET" {
+        return None;
+    }
+    match (request.target.as_str(), response.status) {
+        ("/", 200) => Some(
+            ORIGINAL_INDEX_TEMPLATE
+                .replace("{content_len}", &format!("{}", INDEX_HTML.len() + 2))
+                .replace("{index_html}", INDEX_HTML),
+        ),
+        ("/json", 200) => Some(ORIGINAL_JSON.to_string()),
+        (_, 404) => Some(ORIGINAL_ERROR.to_string()),
+        _ => None,
+    }
+}
+
+/// The body for `/debug/no-blank-line`, whose head is sent without the blank
+/// line that ends it.
+#[tracing::instrument]
+fn no_blank_line() -> Response {
+    tracing::info!("generated reply");
+    Response::new(200)
+        .header("content-type", "text/plain")
+        .body("this body follows the headers with no blank line\n")
+}
+
+/// Answers both `OPTIONS *` (server-wide) and `OPTIONS <path>` for a path
+/// that's routed; every route accepts the same methods, so the two can't
+/// differ. Other paths get the `404` a GET would.
+#[tracing::instrument]
+fn options(allowed: &[&str]) -> Response {
+    tracing::info!("generated reply");
+    Response::new(200).header("allow", allowed.join(", "))
+}
+
+/// Echoes the request head back, minus the headers that carry credentials.
+#[tracing::instrument(skip_all)]
+fn trace(request: &Request) -> Response {
+    let version = request.version.as_deref().unwrap_or("HTTP/0.9");
+    let mut head = format!("{} {} {version}\r\n", request.method, request.target);
+    for (name, value) in &request.headers {
+        if ["authorization", "cookie", "proxy-authorization"]
+            .iter()
+            .any(|sensitive| name.eq_ignore_ascii_case(sensitive))
+        {
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
     tracing::info!("generated reply");
-    JSON.to_string()
+    Response::new(200)
+        .header("content-type", "message/http")
+        .body(head)
 }
 
 #[tracing::instrument]
-fn error() -> String {
+fn not_found() -> Response {
     tracing::info!("generated reply");
-    ERROR.to_string()
+    Response::new(404)
+        .header("content-type", "text/html")
+        .body(NOT_FOUND_HTML)
 }
 
 #[tracing::instrument(skip_all)]
 fn respond(
-    response: String,
+    response: &[u8],
     mut conn: TcpStream,
     mut tls: rustls::ServerConnection,
+    close_notify_delay: Option<Duration>,
 ) -> eyre::Result<()> {
     tracing::info!("starting response");
-    let mut buf = response.as_bytes();
+    let mut buf = response;
     loop {
         if buf.is_empty() {
             tracing::info!("wrote full response");
@@ -204,6 +511,10 @@ fn respond(
 
     tracing::info!("sending closure notification");
     tls.complete_io(&mut conn)?;
+    if let Some(delay) = close_notify_delay {
+        tracing::info!(?delay, "delaying close_notify");
+        std::thread::sleep(delay);
+    }
     tls.send_close_notify();
 
     tracing::info!("flushing write buffer");
@@ -214,16 +525,6 @@ fn respond(
     Ok(())
 }
 
-#[rustfmt::skip]
-const INDEX_HTML_TEMPLATE: &str = r#"HTTP/1.1 200 OK
-content-type: text/html
-content-length: {content_len}
-
-{index_html}
-
-
-"#;
-
 #[rustfmt::skip]
 const INDEX_HTML: &str = r#"<!DOCTYPE html>
 <html>
@@ -267,7 +568,23 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
 "#;
 
 #[rustfmt::skip]
-const JSON: &str = r#"HTTP/1.1 200 OK
+const JSON: &str = r#"{
+    "json": "object"
+}
+"#;
+
+#[rustfmt::skip]
+const ORIGINAL_INDEX_TEMPLATE: &str = r#"HTTP/1.1 200 OK
+content-type: text/html
+content-length: {content_len}
+
+{index_html}
+
+
+"#;
+
+#[rustfmt::skip]
+const ORIGINAL_JSON: &str = r#"HTTP/1.1 200 OK
 content-type: application/json
 
 {
@@ -277,7 +594,7 @@ content-type: application/json
 "#;
 
 #[rustfmt::skip]
-const ERROR: &str = r#"HTTP/1.1 500 INTERNAL SERVER ERROR
+const ORIGINAL_ERROR: &str = r#"HTTP/1.1 500 INTERNAL SERVER ERROR
 content-type: text/html
 
 <html><body>
@@ -286,3 +603,344 @@ Something went wrong
 
 
 "#;
+
+#[rustfmt::skip]
+const NOT_FOUND_HTML: &str = r#"<html><body>
+Not found
+</body></html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use rustls::{ClientConfig, ClientConnection};
+
+    use super::*;
+
+    /// Serves `connections` connections, one after another, on a loopback
+    /// port, sharing one TLS config so later connections can resume.
+    fn spawn_server(config: Config, connections: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let tls_configs = TlsConfigs::new(
+                server_config(&config).unwrap(),
+                &config.early_data_limits,
+                &config.early_data_allow,
+            );
+            let middlewares = Middlewares::from_config(&config);
+            for _ in 0..connections {
+                let (mut conn, _) = listener.accept().unwrap();
+                let tls = tls::accept(&mut conn, &tls_configs).unwrap();
+                serve_once(conn, rustls::Connection::Server(tls), &config, &middlewares).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Sends each of `early` as its own early data record when the client
+    /// has a ticket (or over 1-RTT when it doesn't, or the server rejects
+    /// them), then `rest` after the handshake. Returns the response, checked
+    /// against its `content-length`, and whether the early data was accepted.
+    fn exchange(
+        client: &Arc<ClientConfig>,
+        addr: SocketAddr,
+        early: &[&[u8]],
+        rest: &[u8],
+    ) -> (Vec<u8>, bool) {
+        let (response, accepted) = exchange_unchecked(client, addr, early, rest);
+        load_test::check_framing(&response).unwrap();
+        (response, accepted)
+    }
+
+    /// [`exchange`], without the framing check.
+    fn exchange_unchecked(
+        client: &Arc<ClientConfig>,
+        addr: SocketAddr,
+        early: &[&[u8]],
+        rest: &[u8],
+    ) -> (Vec<u8>, bool) {
+        let mut sock = TcpStream::connect(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let server_name = addr.ip().into();
+        let mut tls = ClientConnection::new(client.clone(), server_name).unwrap();
+
+        let mut sent_early = false;
+        for chunk in early {
+            let Some(mut early_data) = tls.early_data() else {
+                break;
+            };
+            early_data.write_all(chunk).unwrap();
+            sent_early = true;
+            while tls.wants_write() {
+                tls.write_tls(&mut sock).unwrap();
+            }
+        }
+        while tls.is_handshaking() {
+            tls.complete_io(&mut sock).unwrap();
+        }
+
+        let accepted = sent_early && tls.is_early_data_accepted();
+        if !accepted {
+            tls.writer().write_all(&early.concat()).unwrap();
+        }
+        tls.writer().write_all(rest).unwrap();
+
+        let response = load_test::read_response(&mut tls, &mut sock).unwrap();
+        (response, accepted)
+    }
+
+    /// A client holding a ticket from one full handshake with `addr`, so its
+    /// next connection there can resume with early data.
+    fn client_with_ticket(addr: SocketAddr) -> Arc<ClientConfig> {
+        let client = Arc::new(load_test::client_config(true).unwrap());
+        let head = b"GET /json HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (response, _) = exchange(&client, addr, &[], head);
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        client
+    }
+
+    /// Takes two connections on `addr`: one to get a ticket, then an
+    /// [`exchange`] that resumes with it, whose early data must be accepted.
+    fn resume_with_early_data(addr: SocketAddr, early: &[&[u8]], rest: &[u8]) -> Vec<u8> {
+        let client = client_with_ticket(addr);
+        let (response, accepted) = exchange(&client, addr, early, rest);
+        assert!(accepted, "the resumption should have carried early data");
+        response
+    }
+
+    // By default the stream is always read after early data, so the baseline
+    // already handled this split; it's kept as a regression test.
+    #[test]
+    fn request_line_split_across_early_data_and_the_stream() {
+        let addr = spawn_server(Config::default(), 2);
+
+        let response = resume_with_early_data(
+            addr,
+            &[b"GET /js"],
+            b"on HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(JSON.as_bytes()));
+    }
+
+    fn skipping_the_read_after_early_data() -> Config {
+        Config {
+            no_read_after_early_data: true,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn split_request_is_completed_from_the_stream_without_the_usual_read() {
+        let addr = spawn_server(skipping_the_read_after_early_data(), 2);
+
+        // The early data alone is an incomplete head, so the stream still has
+        // to be read for the rest.
+        let response = resume_with_early_data(
+            addr,
+            &[b"GET /js"],
+            b"on HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(JSON.as_bytes()));
+    }
+
+    #[test]
+    fn request_whole_in_early_data_is_served_without_the_usual_read() {
+        let addr = spawn_server(skipping_the_read_after_early_data(), 2);
+
+        let head = b"GET /json HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = resume_with_early_data(addr, &[head], b"");
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(JSON.as_bytes()));
+    }
+
+    #[test]
+    fn early_data_in_two_records_is_drained_whole() {
+        // Without the read after early data, nothing but the two records can
+        // make up the head: if either were left undrained, the server would
+        // wait on the stream for the rest, and nothing more is sent.
+        let addr = spawn_server(skipping_the_read_after_early_data(), 2);
+
+        let response = resume_with_early_data(
+            addr,
+            &[b"GET /json HTTP/1.1\r\n", b"Host: localhost\r\n\r\n"],
+            b"",
+        );
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(JSON.as_bytes()));
+    }
+
+    #[test]
+    fn early_data_deadline_drops_a_stalled_handshake() {
+        let config = Config {
+            early_data_timeout: Some(Duration::from_millis(200)),
+            ..Config::default()
+        };
+        let addr = spawn_server(config, 2);
+        let client = client_with_ticket(addr);
+
+        // Send the front of the request as early data, then stall without
+        // ever finishing the handshake.
+        let mut sock = TcpStream::connect(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut tls = ClientConnection::new(client, addr.ip().into()).unwrap();
+        tls.early_data().unwrap().write_all(b"GET /js").unwrap();
+        while tls.wants_write() {
+            tls.write_tls(&mut sock).unwrap();
+        }
+
+        // The server's flight, then the close, well before the read timeout.
+        let started = Instant::now();
+        let mut flight = Vec::new();
+        sock.read_to_end(&mut flight).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn half_closed_client_still_gets_the_response() {
+        let addr = spawn_server(Config::default(), 1);
+        let client = Arc::new(load_test::client_config(false).unwrap());
+
+        let mut sock = TcpStream::connect(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut tls = ClientConnection::new(client, addr.ip().into()).unwrap();
+        while tls.is_handshaking() {
+            tls.complete_io(&mut sock).unwrap();
+        }
+        tls.writer()
+            .write_all(b"GET /json HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        while tls.wants_write() {
+            tls.write_tls(&mut sock).unwrap();
+        }
+        // The FIN follows the request at once, so the server sees it before
+        // it has a response ready.
+        sock.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let response = load_test::read_response(&mut tls, &mut sock).unwrap();
+        load_test::check_framing(&response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn write_timeout_fires_for_a_client_that_stops_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done, wait) = std::sync::mpsc::channel::<()>();
+        let client = std::thread::spawn(move || {
+            let client = Arc::new(load_test::client_config(false).unwrap());
+            let mut sock = TcpStream::connect(addr).unwrap();
+            let mut tls = ClientConnection::new(client, addr.ip().into()).unwrap();
+            while tls.is_handshaking() {
+                tls.complete_io(&mut sock).unwrap();
+            }
+            // Hold the connection open without ever reading from it.
+            wait.recv().ok();
+        });
+
+        let config = Config::default();
+        let tls_configs = TlsConfigs::new(
+            server_config(&config).unwrap(),
+            &config.early_data_limits,
+            &config.early_data_allow,
+        );
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut tls = tls::accept(&mut conn, &tls_configs).unwrap();
+        while tls.is_handshaking() {
+            tls.complete_io(&mut conn).unwrap();
+        }
+        conn.set_write_timeout(Some(Duration::from_millis(200))).unwrap();
+
+        // Far more than the socket buffers on both ends can hold.
+        let response = vec![b'x'; 64 << 20];
+        let err = respond(&response, conn, tls, None).unwrap_err();
+        assert!(socket::is_timeout(&err), "{err:?}");
+
+        done.send(()).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn original_responses_are_sent_byte_for_byte_and_fail_the_framing_check() {
+        let config = Config {
+            original_responses: true,
+            ..Config::default()
+        };
+        let addr = spawn_server(config, 3);
+        let client = Arc::new(load_test::client_config(false).unwrap());
+        let get = |path: &str| {
+            let head = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            let (response, _) = exchange_unchecked(&client, addr, &[], head.as_bytes());
+            // The index page's content-length is wrong, and the others have
+            // none.
+            assert!(load_test::check_framing(&response).is_err(), "{path}");
+            String::from_utf8(response).unwrap()
+        };
+
+        let index = get("/");
+        let declared = format!("content-length: {}\n", INDEX_HTML.len() + 2);
+        assert!(index.starts_with("HTTP/1.1 200 OK\ncontent-type: text/html\n"));
+        assert!(index.contains(&declared), "{index}");
+        assert!(index.ends_with(&format!("\n\n{INDEX_HTML}\n\n\n")));
+        assert_eq!(get("/json"), ORIGINAL_JSON);
+        assert_eq!(get("/missing"), ORIGINAL_ERROR);
+    }
+
+    #[test]
+    fn api_404_is_json() {
+        let addr = spawn_server(Config::default(), 1);
+        let client = Arc::new(load_test::client_config(false).unwrap());
+
+        let (response, _) = exchange(
+            &client,
+            addr,
+            &[],
+            b"GET /api/missing HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+        assert!(response.contains("\r\ncontent-type: application/json\r\n"), "{response}");
+        assert!(!response.contains("text/html"), "{response}");
+        assert!(
+            response.ends_with("\r\n\r\n{\"error\": \"Not Found\", \"status\": 404}\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn options_on_an_unknown_path_is_not_found() {
+        let addr = spawn_server(Config::default(), 2);
+        let client = Arc::new(load_test::client_config(false).unwrap());
+        let options = |path: &str| {
+            let head = format!("OPTIONS {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            String::from_utf8(exchange(&client, addr, &[], head.as_bytes()).0).unwrap()
+        };
+
+        let response = options("/json");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\nallow: GET, OPTIONS\r\n"), "{response}");
+
+        let response = options("/missing");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+        assert!(!response.contains("\r\nallow:"), "{response}");
+    }
+
+    #[test]
+    fn options_asterisk_lists_the_allowed_methods() {
+        let addr = spawn_server(Config::default(), 1);
+        let client = Arc::new(load_test::client_config(false).unwrap());
+
+        let (response, _) = exchange(
+            &client,
+            addr,
+            &[],
+            b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\nallow: GET, OPTIONS\r\n"), "{response}");
+    }
+}
@@ -0,0 +1,450 @@
+use std::ops::ControlFlow;
+
+use crate::{
+    config::Config,
+    http::{self, Request, Response},
+};
+
+pub type RequestMiddleware = Box<dyn Fn(&mut Request) -> ControlFlow<Response> + Send + Sync>;
+pub type ResponseMiddleware = Box<dyn Fn(&Request, Response) -> Response + Send + Sync>;
+
+/// Cross-cutting request/response handling, kept out of the handlers.
+///
+/// Request middlewares run before routing, in the order they were registered.
+/// Each can rewrite the request, or reject it by breaking with the response to
+/// send instead, in which case the remaining request middlewares and the
+/// handler are skipped.
+///
+/// Response middlewares run after the handler (or the rejecting request
+/// middleware), in the order they were registered, each one getting the
+/// previous one's output.
+#[derive(Default)]
+pub struct Middlewares {
+    request: Vec<RequestMiddleware>,
+    response: Vec<ResponseMiddleware>,
+}
+
+impl Middlewares {
+    pub fn from_config(config: &Config) -> Self {
+        let mut middlewares = Self::default();
+        middlewares.on_request(reject_duplicate_host);
+        if config.require_http11 {
+            middlewares.on_request(require_http11);
+        }
+        middlewares.on_request(allow_methods(allowed_methods(config)));
+        middlewares.on_response(json_errors);
+        middlewares.on_response(upgrade_insecure_requests);
+        middlewares.on_response(cache_control(config.cache_control.clone()));
+        if config.echo_headers {
+            middlewares.on_response(http::echo_headers);
+        }
+        if config.connection_close {
+            middlewares.on_response(connection_close);
+        }
+        middlewares
+    }
+
+    pub fn on_request(
+        &mut self,
+        middleware: impl Fn(&mut Request) -> ControlFlow<Response> + Send + Sync + 'static,
+    ) {
+        self.request.push(Box::new(middleware));
+    }
+
+    pub fn on_response(
+        &mut self,
+        middleware: impl Fn(&Request, Response) -> Response + Send + Sync + 'static,
+    ) {
+        self.response.push(Box::new(middleware));
+    }
+
+    pub fn apply_request(&self, request: &mut Request) -> ControlFlow<Response> {
+        self.request
+            .iter()
+            .try_for_each(|middleware| middleware(request))
+    }
+
+    pub fn apply_response(&self, request: &Request, response: Response) -> Response {
+        self.response.iter().fold(response, |response, middleware| {
+            middleware(request, response)
+        })
+    }
+}
+
+/// Turns an error response into a JSON object for API clients: requests under
+/// `/api`, or that say they `Accept` JSON. Other headers, such as the `allow`
+/// on a `405`, are kept.
+fn json_errors(request: &Request, mut response: Response) -> Response {
+    let path = request.target.split('?').next().unwrap_or_default();
+    let wants_json = path == "/api"
+        || path.starts_with("/api/")
+        || request.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("accept") && value.contains("application/json")
+        });
+    if response.status < 400 || !wants_json {
+        return response;
+    }
+
+    response
+        .headers
+        .retain(|(name, _)| !name.eq_ignore_ascii_case("content-type"));
+    let error = match http::reason(response.status) {
+        "" if response.status >= 500 => "Server Error",
+        "" => "Client Error",
+        reason => reason,
+    };
+    let body = format!(
+        "{{\"error\": \"{error}\", \"status\": {}}}\n",
+        response.status
+    );
+    response
+        .header("content-type", "application/json")
+        .body(body)
+}
+
+/// Answers `Upgrade-Insecure-Requests: 1` with the matching CSP directive.
+///
+/// The usual answer on a plaintext listener is a redirect to HTTPS, but there
+/// is no plaintext listener: everything here is already HTTPS, so the CSP
+/// header, which upgrades the page's own subresource requests, is the whole
+/// of it.
+fn upgrade_insecure_requests(request: &Request, response: Response) -> Response {
+    let asked = request.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("upgrade-insecure-requests") && value == "1"
+    });
+    if !asked {
+        return response;
+    }
+    response
+        .header("content-security-policy", "upgrade-insecure-requests")
+        .header("vary", "Upgrade-Insecure-Requests")
+}
+
+/// Says up front that the connection ends with this response.
+///
+/// Every connection already closes after one response, but without the header
+/// a client only learns that from the close itself, and may have queued its
+/// next request on the connection by then. With it, the client knows to make
+/// a new connection, and that one is where it can try 0-RTT.
+fn connection_close(_request: &Request, response: Response) -> Response {
+    tracing::info!("sending connection: close, the next request needs a new connection");
+    response.header("connection", "close")
+}
+
+/// Routes without a configured `Cache-Control` get this, so a repeat fetch
+/// always reaches the server instead of being answered from the browser cache.
+const DEFAULT_CACHE_CONTROL: &str = "no-store";
+
+/// Sets `Cache-Control` from the per-path `rules`, unless the handler already
+/// set one.
+fn cache_control(
+    rules: Vec<(String, String)>,
+) -> impl Fn(&Request, Response) -> Response + Send + Sync + 'static {
+    move |request, response| {
+        let already_set = response
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("cache-control"));
+        if already_set {
+            return response;
+        }
+
+        let path = request.target.split('?').next().unwrap_or_default();
+        let value = rules
+            .iter()
+            .find(|(route, _)| route == path)
+            .map_or(DEFAULT_CACHE_CONTROL, |(_, value)| value.as_str());
+        response.header("cache-control", value)
+    }
+}
+
+/// The methods the server answers, as advertised in `Allow`.
+pub fn allowed_methods(config: &Config) -> Vec<&'static str> {
+    let mut allowed = vec!["GET", "OPTIONS"];
+    if config.allow_trace {
+        allowed.push("TRACE");
+    }
+    allowed
+}
+
+/// Rejects methods outside `allowed` with `405`. TRACE is only on the list when
+/// explicitly enabled, since echoing requests back aids cross-site tracing.
+fn allow_methods(
+    allowed: Vec<&'static str>,
+) -> impl Fn(&mut Request) -> ControlFlow<Response> + Send + Sync + 'static {
+    let allow = allowed.join(", ");
+    move |request| {
+        if allowed.contains(&request.method.as_str()) {
+            return ControlFlow::Continue(());
+        }
+
+        tracing::warn!(method = request.method, allow, "method not allowed");
+        ControlFlow::Break(Response::new(405).header("allow", allow.clone()))
+    }
+}
+
+/// Rejects anything but HTTP/1.1 with `505`.
+///
+/// The response has a body because that's all an HTTP/0.9 client gets: 0.9
+/// has no status line, so a bodiless rejection would look like being served
+/// an empty document.
+fn require_http11(request: &mut Request) -> ControlFlow<Response> {
+    if request.version.as_deref() == Some("HTTP/1.1") {
+        return ControlFlow::Continue(());
+    }
+
+    let version = request.version.as_deref().unwrap_or("HTTP/0.9");
+    tracing::warn!(version, "only HTTP/1.1 is accepted");
+    ControlFlow::Break(
+        Response::new(505)
+            .header("content-type", "text/plain")
+            .body(format!("{version} is not supported, only HTTP/1.1 is\n")),
+    )
+}
+
+/// Rejects a request with more than one `Host` header, since front ends and
+/// back ends that pick different ones can be smuggled past each other.
+fn reject_duplicate_host(request: &mut Request) -> ControlFlow<Response> {
+    let hosts = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .count();
+    if hosts <= 1 {
+        return ControlFlow::Continue(());
+    }
+
+    tracing::warn!(hosts, "rejecting request with duplicate host headers");
+    ControlFlow::Break(Response::new(400))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request;
+
+    #[test]
+    fn response_middlewares_compose_in_registration_order() {
+        let mut middlewares = Middlewares::default();
+        middlewares.on_response(|_, response: Response| response.header("x-order", "first"));
+        middlewares.on_response(|_, response: Response| {
+            let previous = response.headers.last().map(|(_, value)| value.clone());
+            response.header("x-order", format!("second, after {previous:?}"))
+        });
+
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+        let response = middlewares.apply_response(&request, Response::new(200));
+        assert_eq!(
+            response.headers,
+            [
+                ("x-order".to_string(), "first".to_string()),
+                (
+                    "x-order".to_string(),
+                    "second, after Some(\"first\")".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn request_middleware_short_circuits() {
+        let mut middlewares = Middlewares::default();
+        middlewares.on_request(|request: &mut Request| {
+            let authorized = request
+                .headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("authorization"));
+            if authorized {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(Response::new(401))
+            }
+        });
+        middlewares.on_request(|_: &mut Request| panic!("ran after a short circuit"));
+
+        let mut request = request("GET / HTTP/1.1\r\n\r\n");
+        let ControlFlow::Break(response) = middlewares.apply_request(&mut request) else {
+            panic!("unauthorized request was let through");
+        };
+        assert_eq!(response.status, 401);
+    }
+
+    #[test]
+    fn api_errors_are_json_whatever_the_query() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        for target in ["/api?x=1", "/api/?x", "/api/missing?v=2"] {
+            let request = request(&format!("GET {target} HTTP/1.1\r\n\r\n"));
+            let response = Response::new(404)
+                .header("content-type", "text/html")
+                .body("<html></html>");
+            let response = middlewares.apply_response(&request, response);
+            assert!(
+                response
+                    .headers
+                    .contains(&("content-type".to_string(), "application/json".to_string())),
+                "{target}"
+            );
+            assert_eq!(
+                response.body,
+                "{\"error\": \"Not Found\", \"status\": 404}\n"
+            );
+        }
+    }
+
+    #[test]
+    fn non_api_errors_are_left_alone() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let request = request("GET /apiary HTTP/1.1\r\n\r\n");
+        let response = middlewares.apply_response(&request, Response::new(404).body("<html>"));
+        assert_eq!(response.body, "<html>");
+    }
+
+    #[test]
+    fn json_errors_fall_back_to_a_generic_phrase() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let request = request("GET /api HTTP/1.1\r\n\r\n");
+        let response = middlewares.apply_response(&request, Response::new(418));
+        assert_eq!(
+            response.body,
+            "{\"error\": \"Client Error\", \"status\": 418}\n"
+        );
+        let response = middlewares.apply_response(&request, Response::new(503));
+        assert_eq!(
+            response.body,
+            "{\"error\": \"Server Error\", \"status\": 503}\n"
+        );
+    }
+
+    #[test]
+    fn duplicate_host_headers_are_rejected() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let mut request = request("GET / HTTP/1.1\r\nHost: a.example\r\nhost: b.example\r\n\r\n");
+        let ControlFlow::Break(response) = middlewares.apply_request(&mut request) else {
+            panic!("duplicate Host headers were let through");
+        };
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn single_host_header_is_let_through() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let mut request = request("GET / HTTP/1.1\r\nHost: a.example\r\n\r\n");
+        assert!(middlewares.apply_request(&mut request).is_continue());
+    }
+
+    #[test]
+    fn upgrade_insecure_requests_gets_the_csp_directive() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let csp = (
+            "content-security-policy".to_string(),
+            "upgrade-insecure-requests".to_string(),
+        );
+
+        let asked = request("GET / HTTP/1.1\r\nUpgrade-Insecure-Requests: 1\r\n\r\n");
+        let response = middlewares.apply_response(&asked, Response::new(200));
+        assert!(response.headers.contains(&csp));
+
+        let plain = request("GET / HTTP/1.1\r\n\r\n");
+        let response = middlewares.apply_response(&plain, Response::new(200));
+        assert!(!response.headers.contains(&csp));
+    }
+
+    fn cache_control_of(response: &Response) -> Vec<&str> {
+        response
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "cache-control")
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn configured_cache_control_is_applied_per_path() {
+        let config = Config {
+            cache_control: vec![("/json".to_string(), "max-age=3600".to_string())],
+            ..Config::default()
+        };
+        let middlewares = Middlewares::from_config(&config);
+
+        let json = request("GET /json?v=2 HTTP/1.1\r\n\r\n");
+        let response = middlewares.apply_response(&json, Response::new(200));
+        assert_eq!(cache_control_of(&response), ["max-age=3600"]);
+
+        let index = request("GET / HTTP/1.1\r\n\r\n");
+        let response = middlewares.apply_response(&index, Response::new(200));
+        assert_eq!(cache_control_of(&response), [DEFAULT_CACHE_CONTROL]);
+    }
+
+    #[test]
+    fn cache_control_set_by_the_handler_is_kept() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+        let response = Response::new(200).header("cache-control", "private");
+        let response = middlewares.apply_response(&request, response);
+        assert_eq!(cache_control_of(&response), ["private"]);
+    }
+
+    fn require_http11_config() -> Config {
+        Config {
+            require_http11: true,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn require_http11_rejects_http10() {
+        let middlewares = Middlewares::from_config(&require_http11_config());
+        let mut request = request("GET / HTTP/1.0\r\n\r\n");
+        let ControlFlow::Break(response) = middlewares.apply_request(&mut request) else {
+            panic!("HTTP/1.0 request was let through");
+        };
+        assert_eq!(response.status, 505);
+    }
+
+    #[test]
+    fn require_http11_rejects_http09_with_a_visible_error() {
+        let middlewares = Middlewares::from_config(&require_http11_config());
+        let mut request = request("GET /\r\n");
+        let ControlFlow::Break(response) = middlewares.apply_request(&mut request) else {
+            panic!("HTTP/0.9 request was let through");
+        };
+        assert_eq!(response.status, 505);
+
+        // 0.9 gets the bare body, so it's the only sign of the rejection.
+        let encoded = http::encode_response(&response, &request.method, None);
+        assert_eq!(encoded, b"HTTP/0.9 is not supported, only HTTP/1.1 is\n");
+    }
+
+    #[test]
+    fn require_http11_lets_http11_through() {
+        let middlewares = Middlewares::from_config(&require_http11_config());
+        let mut request = request("GET / HTTP/1.1\r\n\r\n");
+        assert!(middlewares.apply_request(&mut request).is_continue());
+    }
+
+    #[test]
+    fn trace_is_rejected_by_default() {
+        let middlewares = Middlewares::from_config(&Config::default());
+        let mut request = request("TRACE / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let ControlFlow::Break(response) = middlewares.apply_request(&mut request) else {
+            panic!("TRACE was let through");
+        };
+        assert_eq!(response.status, 405);
+        assert!(
+            response
+                .headers
+                .contains(&("allow".to_string(), "GET, OPTIONS".to_string()))
+        );
+    }
+
+    #[test]
+    fn trace_is_let_through_with_allow_trace() {
+        let config = Config {
+            allow_trace: true,
+            ..Config::default()
+        };
+        let middlewares = Middlewares::from_config(&config);
+        let mut request = request("TRACE / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(middlewares.apply_request(&mut request).is_continue());
+    }
+}
@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+
+use crate::{cidr::Cidr, http::Request};
+
+/// Works out which address a request really came from.
+///
+/// When the connection comes from a trusted proxy, the forwarding chain is
+/// walked from the nearest hop outwards, and the first hop that isn't itself a
+/// trusted proxy is the client. `Forwarded` wins over `X-Forwarded-For` when
+/// both are present. Forwarding headers from untrusted peers are ignored, as
+/// anyone can send them.
+pub fn client_ip(peer: IpAddr, request: &Request, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let mut hops = forwarded_hops(request, "forwarded");
+    if hops.is_empty() {
+        hops = forwarded_hops(request, "x-forwarded-for");
+    }
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        if !is_trusted(client) {
+            break;
+        }
+        // An obfuscated or `unknown` hop can't be looked past.
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+    }
+    client
+}
+
+/// Collects the client addresses from every `header` field, in order.
+fn forwarded_hops(request: &Request, header: &str) -> Vec<Option<IpAddr>> {
+    let values = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(header))
+        .flat_map(|(_, value)| value.split(','));
+
+    if header == "forwarded" {
+        values
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect()
+    } else {
+        values.map(parse_node).collect()
+    }
+}
+
+/// Parses a node as found in `Forwarded: for=` or `X-Forwarded-For`, such as
+/// `192.0.2.1`, `"192.0.2.1:4711"`, `2001:db8::1` or `"[2001:db8::1]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let (ip, _port) = node.split_once(':')?;
+        ip.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn trusted() -> Vec<Cidr> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let request = request(
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 192.0.2.1\r\nForwarded: for=192.0.2.2\r\n\r\n",
+        );
+        assert_eq!(
+            client_ip(ip("203.0.113.9"), &request, &trusted()),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn trusted_peer_forwards_the_client() {
+        let request = request("GET / HTTP/1.1\r\nX-Forwarded-For: 192.0.2.1\r\n\r\n");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &request, &trusted()),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn chain_stops_at_the_first_untrusted_hop() {
+        // 192.0.2.66 claims to have forwarded for 192.0.2.1, but it isn't a
+        // trusted proxy, so that claim can't be believed.
+        let request =
+            request("GET / HTTP/1.1\r\nX-Forwarded-For: 192.0.2.1, 192.0.2.66, 10.0.0.2\r\n\r\n");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &request, &trusted()),
+            ip("192.0.2.66")
+        );
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let request = request(
+            "GET / HTTP/1.1\r\n\
+             X-Forwarded-For: 192.0.2.1\r\n\
+             Forwarded: for=\"[2001:db8::1]:4711\";proto=https\r\n\r\n",
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &request, &trusted()),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn unknown_hop_is_not_looked_past() {
+        let request = request("GET / HTTP/1.1\r\nForwarded: for=192.0.2.1, for=unknown\r\n\r\n");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &request, &trusted()),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::Mutex;
+
+use rustls::crypto::{GetRandomFailed, SecureRandom};
+
+/// A `SecureRandom` that replays the same stream every run, so the session
+/// tickets the server issues are the same bytes run to run.
+///
+/// NEVER use this in production. Everything rustls draws from it is
+/// predictable from the seed: ticket ids (which are the lookup keys for
+/// resumable sessions), ticket nonces and age_add, and the ServerHello random.
+///
+/// This seeds randomness rather than implementing a seeded `ProducesTickets`,
+/// because rustls refuses early data whenever a ticketer is set (stateless
+/// tickets can't be protected against replay), which would rule out the 0-RTT
+/// path this is meant to capture. Key shares come from the crypto library's
+/// own RNG and still differ run to run, and connections served concurrently
+/// draw from the stream in whatever order they happen to run.
+#[derive(Debug)]
+pub struct SeededRandom {
+    state: Mutex<u64>,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Mutex::new(seed),
+        }
+    }
+}
+
+impl SecureRandom for SeededRandom {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), GetRandomFailed> {
+        let mut state = self.state.lock().map_err(|_| GetRandomFailed)?;
+        for chunk in buf.chunks_mut(8) {
+            let bytes = splitmix64(&mut state).to_be_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// Steps Sebastiano Vigna's SplitMix64 generator.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,137 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use socket2::{SockRef, TcpKeepalive};
+
+/// Turns on TCP keepalive for an accepted connection, so a peer that vanished
+/// without a FIN is eventually noticed and the connection reaped.
+///
+/// Probes start after `idle` and repeat every `idle`, where the platform lets
+/// us pick the interval; elsewhere only the idle time is set and the OS
+/// defaults apply to the rest.
+pub fn set_keepalive(conn: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    let keepalive = keepalive.with_interval(idle);
+
+    SockRef::from(conn).set_tcp_keepalive(&keepalive)
+}
+
+/// Checks, without blocking, whether the peer has already hung up.
+///
+/// Only a reset counts. A FIN alone is not a disconnect: a client may
+/// half-close its side (`shutdown(SHUT_WR)`) once the request is sent and
+/// still read the response. A peer that closed entirely is only found out
+/// when the response write fails, which [`is_disconnect`] recognizes.
+pub fn peer_disconnected(conn: &TcpStream) -> bool {
+    if conn.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let disconnected = match conn.peek(&mut [0]) {
+        Ok(_) => false,
+        Err(err) => is_disconnect_kind(err.kind()),
+    };
+    let _ = conn.set_nonblocking(false);
+    disconnected
+}
+
+/// Whether `err` is the peer having gone away: a reset, or a write to a
+/// connection it already closed.
+pub fn is_disconnect(err: &eyre::Report) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| is_disconnect_kind(err.kind()))
+}
+
+fn is_disconnect_kind(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
+
+/// Whether `err` is a socket read or write timing out.
+///
+/// Unix reports an expired `SO_RCVTIMEO`/`SO_SNDTIMEO` as `WouldBlock`, and
+/// Windows as `TimedOut`.
+pub fn is_timeout(err: &eyre::Report) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| is_timeout_kind(err.kind()))
+}
+
+fn is_timeout_kind(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Wraps a socket so that, once armed, every read made through it gives up at
+/// the deadline, and remembers when one did.
+///
+/// A write timing out fails with the same error kinds as a read, so the error
+/// alone can't say whether the deadline fired; [`ReadDeadline::expired`] can.
+/// Writes pass straight through, under whatever write timeout the socket has.
+pub struct ReadDeadline<'a> {
+    conn: &'a TcpStream,
+    deadline: Option<Instant>,
+    expired: bool,
+}
+
+impl<'a> ReadDeadline<'a> {
+    pub fn new(conn: &'a TcpStream) -> Self {
+        Self {
+            conn,
+            deadline: None,
+            expired: false,
+        }
+    }
+
+    pub fn arm(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Whether a read gave up because the deadline passed.
+    pub fn expired(&self) -> bool {
+        self.expired
+    }
+
+    /// Stops bounding reads, and takes the read timeout back off the socket.
+    pub fn disarm(&mut self) -> io::Result<()> {
+        if self.deadline.take().is_some() {
+            self.conn.set_read_timeout(None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for ReadDeadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(deadline) = self.deadline else {
+            return self.conn.read(buf);
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            self.expired = true;
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.conn.set_read_timeout(Some(remaining))?;
+        let result = self.conn.read(buf);
+        if let Err(err) = &result
+            && is_timeout_kind(err.kind())
+        {
+            self.expired = true;
+        }
+        result
+    }
+}
+
+impl Write for ReadDeadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+    sync::Arc,
+};
+
+use rustls::server::{Acceptor, ServerConfig, ServerConnection};
+
+use crate::cidr::Cidr;
+
+/// The server config, plus variants of it that only differ in
+/// `max_early_data_size`, keyed by ALPN protocol id.
+///
+/// rustls reads the early data limit off the `ServerConfig`, so the only way
+/// to vary it per connection is to pick the config after seeing the
+/// ClientHello. The variants are clones of the base config, which means they
+/// share its session storage and tickets issued under one resume under the
+/// others.
+///
+/// The server doesn't negotiate ALPN itself (it only speaks HTTP/1.1), so the
+/// limit is chosen from the client's most preferred offered protocol that has
+/// one configured. Clients size their early data off the limit in the ticket
+/// they were issued, so a resumption that lands on a smaller limit than it was
+/// ticketed with can have its early data cut off by rustls.
+///
+/// When `early_data_allow` is non-empty, peers outside it get a variant with
+/// `max_early_data_size` of 0 whatever they offer, so rustls rejects their
+/// early data and the request has to come over 1-RTT.
+#[derive(Debug)]
+pub struct TlsConfigs {
+    default: Arc<ServerConfig>,
+    per_alpn: Vec<(Vec<u8>, Arc<ServerConfig>)>,
+    early_data_allow: Vec<Cidr>,
+    no_early_data: Arc<ServerConfig>,
+}
+
+impl TlsConfigs {
+    pub fn new(
+        default: Arc<ServerConfig>,
+        early_data_limits: &[(String, u32)],
+        early_data_allow: &[Cidr],
+    ) -> Self {
+        let with_limit = |limit| {
+            let mut config = (*default).clone();
+            config.max_early_data_size = limit;
+            Arc::new(config)
+        };
+        let per_alpn = early_data_limits
+            .iter()
+            .map(|(protocol, limit)| (protocol.as_bytes().to_vec(), with_limit(*limit)))
+            .collect();
+        let no_early_data = with_limit(0);
+
+        Self {
+            default,
+            per_alpn,
+            early_data_allow: early_data_allow.to_vec(),
+            no_early_data,
+        }
+    }
+
+    /// Whether `peer` may send early data at all.
+    fn allows_early_data(&self, peer: IpAddr) -> bool {
+        self.early_data_allow.is_empty()
+            || self.early_data_allow.iter().any(|cidr| cidr.contains(peer))
+    }
+
+    fn select<'a>(
+        &self,
+        peer: IpAddr,
+        mut offered: impl Iterator<Item = &'a [u8]>,
+    ) -> &Arc<ServerConfig> {
+        if !self.allows_early_data(peer) {
+            return &self.no_early_data;
+        }
+        offered
+            .find_map(|protocol| {
+                self.per_alpn
+                    .iter()
+                    .find(|(configured, _)| configured == protocol)
+                    .map(|(_, config)| config)
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Reads the ClientHello off `conn` and starts a connection with the config
+/// that matches it.
+pub fn accept(conn: &mut TcpStream, configs: &TlsConfigs) -> eyre::Result<ServerConnection> {
+    let peer = conn.peer_addr()?.ip();
+    let mut conn = RecordTrace::new(conn);
+    let mut acceptor = Acceptor::default();
+    let accepted = loop {
+        if acceptor.read_tls(&mut conn)? == 0 {
+            eyre::bail!("connection closed before the ClientHello arrived");
+        }
+        match acceptor.accept() {
+            Ok(Some(accepted)) => break accepted,
+            Ok(None) => continue,
+            Err((err, mut alert)) => {
+                alert.write_all(&mut conn)?;
+                return Err(err.into());
+            }
+        }
+    };
+
+    let hello = accepted.client_hello();
+    // Browsers leave SNI out for IP-address URLs like https://127.0.0.1:3000,
+    // but send it for https://localhost:3000.
+    tracing::info!(
+        sni = hello.server_name().unwrap_or("no_sni"),
+        "client hello"
+    );
+    let offered = hello.alpn().into_iter().flatten();
+    let config = configs.select(peer, offered).clone();
+    let alpn = hello
+        .alpn()
+        .map(|offered| {
+            offered
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    tracing::info!(
+        alpn,
+        %peer,
+        early_data_allowed = configs.allows_early_data(peer),
+        max_early_data_size = config.max_early_data_size,
+        "chose early data limit"
+    );
+
+    match accepted.into_connection(config) {
+        Ok(tls) => Ok(tls),
+        Err((err, mut alert)) => {
+            alert.write_all(&mut conn)?;
+            Err(err.into())
+        }
+    }
+}
+
+/// Wraps the socket during the handshake and logs, at trace level, each TLS
+/// record that crosses it and the handshake messages inside where they're
+/// readable.
+///
+/// rustls has no hook for observing handshake messages, so this works off the
+/// record layer instead, which limits what it can see. Only messages sent
+/// before encryption starts can be named: the ClientHello and ServerHello in
+/// TLS 1.3 (plus the whole TLS 1.2 flight up to ChangeCipherSpec). Everything
+/// after that (EncryptedExtensions, Certificate, Finished, NewSessionTicket,
+/// early data and EndOfEarlyData) shows up only as encrypted records and their
+/// sizes; `serve_once` logs what rustls reports about the outcome once the
+/// handshake ends.
+///
+/// While tracing, reads stop at record boundaries so that a new `RecordTrace`
+/// over the same socket starts in step with the record stream. That means
+/// more, smaller reads than usual, which can shift IO timing a little.
+pub struct RecordTrace<'a, T> {
+    inner: &'a mut T,
+    enabled: bool,
+    received: Direction,
+    sent: Direction,
+}
+
+#[derive(Default)]
+struct Direction {
+    pending: Vec<u8>,
+    encrypted: bool,
+}
+
+impl<'a, T> RecordTrace<'a, T> {
+    pub fn new(inner: &'a mut T) -> Self {
+        Self {
+            inner,
+            enabled: tracing::enabled!(tracing::Level::TRACE),
+            received: Direction::default(),
+            sent: Direction::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for RecordTrace<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.enabled {
+            return self.inner.read(buf);
+        }
+
+        let limit = buf.len().min(self.received.left_in_record());
+        let read = self.inner.read(&mut buf[..limit])?;
+        self.received.observe("received", &buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for RecordTrace<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if self.enabled {
+            self.sent.observe("sent", &buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Direction {
+    /// Bytes until the end of the record currently being received.
+    fn left_in_record(&self) -> usize {
+        match self.pending.get(3..5) {
+            Some(&[a, b]) => 5 + usize::from(u16::from_be_bytes([a, b])) - self.pending.len(),
+            _ => 5 - self.pending.len(),
+        }
+    }
+
+    fn observe(&mut self, direction: &'static str, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+
+        while self.pending.len() >= 5 {
+            let content_type = self.pending[0];
+            let len = usize::from(u16::from_be_bytes([self.pending[3], self.pending[4]]));
+            if self.pending.len() < 5 + len {
+                break;
+            }
+            let record: Vec<u8> = self.pending.drain(..5 + len).skip(5).collect();
+
+            match content_type {
+                20 => {
+                    tracing::trace!(direction, len, "ChangeCipherSpec record");
+                    self.encrypted = true;
+                }
+                21 => tracing::trace!(direction, len, "alert record"),
+                22 if !self.encrypted => {
+                    let mut messages = record.as_slice();
+                    while let [kind, a, b, c, rest @ ..] = messages {
+                        let message_len = u32::from_be_bytes([0, *a, *b, *c]) as usize;
+                        let message = handshake_message_name(*kind);
+                        tracing::trace!(direction, message, message_len, "handshake message");
+                        messages = rest.get(message_len..).unwrap_or_default();
+                    }
+                }
+                22 => tracing::trace!(direction, len, "encrypted handshake record"),
+                23 => tracing::trace!(direction, len, "encrypted record"),
+                _ => tracing::trace!(direction, content_type, len, "unknown record"),
+            }
+        }
+    }
+}
+
+fn handshake_message_name(kind: u8) -> &'static str {
+    match kind {
+        1 => "ClientHello",
+        2 => "ServerHello",
+        4 => "NewSessionTicket",
+        5 => "EndOfEarlyData",
+        8 => "EncryptedExtensions",
+        11 => "Certificate",
+        12 => "ServerKeyExchange",
+        13 => "CertificateRequest",
+        14 => "ServerHelloDone",
+        15 => "CertificateVerify",
+        16 => "ClientKeyExchange",
+        20 => "Finished",
+        _ => "unknown",
+    }
+}
+
+/// Counts the `complete_io` calls one phase of a connection makes, and how
+/// many in a row moved no bytes.
+///
+/// A loop that keeps calling `complete_io` without anything arriving or
+/// leaving is stuck, even though it isn't blocked; with a `cap`, that many
+/// fruitless calls in a row is an error instead of a spin.
+pub struct IoProgress {
+    phase: &'static str,
+    cap: Option<usize>,
+    pub calls: usize,
+    stalled: usize,
+}
+
+impl IoProgress {
+    pub fn new(phase: &'static str, cap: Option<usize>) -> Self {
+        Self {
+            phase,
+            cap,
+            calls: 0,
+            stalled: 0,
+        }
+    }
+
+    /// Records one `complete_io` call, and whether it (or the read that
+    /// followed) moved any bytes.
+    pub fn record(&mut self, progressed: bool) -> eyre::Result<()> {
+        self.calls += 1;
+        self.stalled = if progressed { 0 } else { self.stalled + 1 };
+        if let Some(cap) = self.cap
+            && self.stalled >= cap
+        {
+            eyre::bail!(
+                "no progress in {} phase after {} complete_io calls ({} in a row moved nothing)",
+                self.phase,
+                self.calls,
+                self.stalled
+            );
+        }
+        Ok(())
+    }
+}